@@ -1,21 +1,22 @@
 use keyboarder::{
     platform_impl::Simulator,
-    simulate::Simulate,
+    simulate::{Simulate, SimulatorHandle},
     types::{ServerMode, SimEvent},
 };
 use std::{
     io::{BufReader, Read},
     net::{TcpListener, TcpStream},
     process::exit,
+    sync::Arc,
 };
 
-fn handle_connection(mut stream: TcpStream) -> anyhow::Result<()> {
+fn handle_connection(handle: &SimulatorHandle, mut stream: TcpStream) -> anyhow::Result<()> {
     let mut buf_reader = BufReader::new(&mut stream);
     let mut http_request = Vec::new();
     let _size = buf_reader.read_to_end(&mut http_request)?;
 
     let sim_event = SimEvent::try_from(http_request)?;
-    Simulator::event_to_server(&sim_event)
+    Ok(handle.send(&sim_event)?)
 }
 
 fn main() -> anyhow::Result<()> {
@@ -23,10 +24,12 @@ fn main() -> anyhow::Result<()> {
     std::env::set_var("DISPLAY", ":0");
 
     let listener = TcpListener::bind("0.0.0.0:7878")?;
-    let _handle = Simulator::spawn_server(ServerMode::Translate)?;
+    let handle = Arc::new(Simulator::spawn_server(ServerMode::Translate)?);
 
+    let ctrlc_handle = Arc::clone(&handle);
     ctrlc::set_handler(move || {
-        Simulator::event_to_server(&SimEvent::ExitThread)
+        ctrlc_handle
+            .shutdown()
             .map_err(|err| log::error!("Failed to exit thread: {:?}", err))
             .ok();
         exit(0)
@@ -37,9 +40,10 @@ fn main() -> anyhow::Result<()> {
         match stream {
             Ok(stream) => {
                 let stream: TcpStream = stream;
-                if let Err(err) = handle_connection(stream) {
-                    Simulator::event_to_server(&SimEvent::ReleaseKeys)
-                        .map_err(|err| log::error!("Failed to exit thread: {:?}", err))
+                if let Err(err) = handle_connection(&handle, stream) {
+                    handle
+                        .release_keys()
+                        .map_err(|err| log::error!("Failed to release keys: {:?}", err))
                         .ok();
                     log::error!("simulate err: {:?}", err);
                 }
@@ -12,18 +12,18 @@ fn main() -> anyhow::Result<()> {
     let mut simulator = Simulator::new(&conn);
 
     // & in French
-    simulator.simulate_keycode(10, true);
-    simulator.simulate_keycode(10, false);
+    simulator.simulate_keycode(10, true)?;
+    simulator.simulate_keycode(10, false)?;
 
-    simulator.simulate_char_without_modifiers('1');
-    simulator.simulate_char_without_modifiers('1');
-    simulator.simulate_char_without_modifiers('!');
-    simulator.simulate_char_without_modifiers('!');
+    simulator.simulate_char_without_modifiers('1')?;
+    simulator.simulate_char_without_modifiers('1')?;
+    simulator.simulate_char_without_modifiers('!')?;
+    simulator.simulate_char_without_modifiers('!')?;
 
-    simulator.simulate_char_without_modifiers('¹');
-    simulator.simulate_char_without_modifiers('¹');
-    simulator.simulate_char_without_modifiers('¡');
-    simulator.simulate_char_without_modifiers('¡');
+    simulator.simulate_char_without_modifiers('¹')?;
+    simulator.simulate_char_without_modifiers('¹')?;
+    simulator.simulate_char_without_modifiers('¡')?;
+    simulator.simulate_char_without_modifiers('¡')?;
 
     Ok(())
 }
@@ -1,7 +1,7 @@
 use keyboarder::{
     platform_impl::Simulator,
     simulate::Simulate,
-    types::{KeyCode, KeyEvent, Modifiers, ServerMode, SimEvent},
+    types::{KeyCode, KeyEvent, Modifiers, ServerMode},
 };
 
 fn main() -> anyhow::Result<()> {
@@ -15,8 +15,9 @@ fn main() -> anyhow::Result<()> {
         raw_event: None,
     };
 
-    Simulator::spawn_server(ServerMode::Translate)?;
-    Simulator::event_to_server(&SimEvent::Simulate(key_event))?;
+    let handle = Simulator::spawn_server(ServerMode::Translate)?;
+    handle.simulate(key_event)?;
+    handle.shutdown()?;
 
     Ok(())
 }
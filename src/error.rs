@@ -0,0 +1,69 @@
+use crate::types::{KeyEvent, PhysKeyCode};
+
+/// The failures of the simulation that callers may want to handle.
+#[derive(Debug, thiserror::Error)]
+pub enum KeyboarderError {
+    #[error("No keycode for keysym {keysym:#x} in {layout:?}")]
+    NoKeycodeForKeysym { keysym: u32, layout: String },
+    #[error("No keycode for {phys:?} in {layout:?}")]
+    UnknownPhysKey { phys: PhysKeyCode, layout: String },
+    #[error("No spare keycode to remap keysym {0:#x}")]
+    OutOfSpareKeycodes(u32),
+    #[error("X connection is broken")]
+    XConnectionBroken(#[source] anyhow::Error),
+    #[error("Unexpected keycode {0}, keycode should be in (8, 255)")]
+    InvalidKeycode(u32),
+    #[error("Can't find simulate mode")]
+    ModeNotSet,
+    #[error("Unexpected key event {0:?} in this mode")]
+    UnexpectedKeyEvent(KeyEvent),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+pub type Result<T, E = KeyboarderError> = std::result::Result<T, E>;
+
+impl From<anyhow::Error> for KeyboarderError {
+    /// Keep the variant of a `KeyboarderError` which went through anyhow.
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<KeyboarderError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        if is_connection_error(&err) {
+            Self::XConnectionBroken(err)
+        } else {
+            Self::Other(err)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_connection_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.is::<xcb::ConnError>()
+            || matches!(
+                cause.downcast_ref::<xcb::Error>(),
+                Some(xcb::Error::Connection(_))
+            )
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_connection_error(_err: &anyhow::Error) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+impl From<xcb::Error> for KeyboarderError {
+    fn from(err: xcb::Error) -> Self {
+        anyhow::Error::from(err).into()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<xcb::ConnError> for KeyboarderError {
+    fn from(err: xcb::ConnError) -> Self {
+        Self::XConnectionBroken(err.into())
+    }
+}
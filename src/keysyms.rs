@@ -3,7 +3,75 @@ use std::collections::HashMap;
 pub struct CharKeySymMap {
     pub keysym_to_char: HashMap<u32, u32>,
     pub char_to_keysym: HashMap<u32, u32>,
+    pub char_to_name: HashMap<u32, &'static str>,
 }
+/// Modifier keysyms without a `PhysKeyCode`.
+pub const KEY_SUPER_L: u32 = 0xffeb;
+pub const KEY_HYPER_L: u32 = 0xffed;
+pub const KEY_ISO_LEVEL5_SHIFT: u32 = 0xfe11;
+
+/// All the dead keysyms, from dead_grave to dead_longsolidusoverlay. The
+/// gaps hold the AccessX controls (0xfe70..=0xfe7a) and unassigned keysyms.
+pub const DEAD_KEYSYMS: [std::ops::RangeInclusive<u32>; 3] =
+    [0xfe50..=0xfe6f, 0xfe80..=0xfe8d, 0xfe90..=0xfe93];
+
+/// A dead key: the accent it adds to the next char, and the char it types
+/// before a space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadKey {
+    pub keysym: u32,
+    /// The end of the names of the accented keysyms, e.g. "acircumflex".
+    pub accent: &'static str,
+    pub spacing: char,
+}
+
+/// The spacing chars are the ones of the en_US Compose file.
+pub const DEAD_KEYS: [DeadKey; 13] = [
+    dead_key(0xfe50, "grave", '`'),
+    dead_key(0xfe51, "acute", '\''),
+    dead_key(0xfe52, "circumflex", '^'),
+    dead_key(0xfe53, "tilde", '~'),
+    dead_key(0xfe54, "macron", '¯'),
+    dead_key(0xfe55, "breve", '˘'),
+    dead_key(0xfe56, "abovedot", '˙'),
+    dead_key(0xfe57, "diaeresis", '"'),
+    dead_key(0xfe58, "ring", '°'),
+    dead_key(0xfe59, "doubleacute", '˝'),
+    dead_key(0xfe5a, "caron", 'ˇ'),
+    dead_key(0xfe5b, "cedilla", '¸'),
+    dead_key(0xfe5c, "ogonek", '˛'),
+];
+
+const fn dead_key(keysym: u32, accent: &'static str, spacing: char) -> DeadKey {
+    DeadKey {
+        keysym,
+        accent,
+        spacing,
+    }
+}
+
+pub fn is_dead_keysym(keysym: u32) -> bool {
+    DEAD_KEYSYMS.iter().any(|range| range.contains(&keysym))
+}
+
+/// The dead key and the base char typing `chr`: the bare accent comes with a
+/// space, an accented letter with the letter.
+pub fn decompose_dead_key(chr: char) -> Option<(&'static DeadKey, char)> {
+    if let Some(dead_key) = DEAD_KEYS.iter().find(|dead_key| dead_key.spacing == chr) {
+        return Some((dead_key, ' '));
+    }
+
+    // "acircumflex" => a + dead_circumflex
+    let name = CHAR_KEYSYM_MAP.char_to_name.get(&(chr as u32))?;
+    DEAD_KEYS.iter().find_map(|dead_key| {
+        let mut base = name.strip_suffix(dead_key.accent)?.chars();
+        match (base.next(), base.next()) {
+            (Some(base), None) if base.is_ascii_alphabetic() => Some((dead_key, base)),
+            _ => None,
+        }
+    })
+}
+
 lazy_static::lazy_static! {
     pub static ref CHAR_KEYSYM_MAP: CharKeySymMap = build_char_keysym();
 }
@@ -12,7 +80,7 @@ lazy_static::lazy_static! {
 ///
 pub fn build_char_keysym() -> CharKeySymMap {
     // (name keysym codepoint)
-    let symbols: [(&str, u32, char); 1032] = [
+    let symbols: [(&str, u32, char); 1033] = [
         ("P", 80, '\u{50}'),
         ("Pabovedot", 16784982, '\u{1e56}'),
         ("PesetaSign", 16785575, '\u{20a7}'),
@@ -581,6 +649,7 @@ pub fn build_char_keysym() -> CharKeySymMap {
         ("crossinglines", 2542, '\u{253c}'),
         ("cuberoot", 16785947, '\u{221b}'),
         ("currency", 164, '\u{a4}'),
+        ("EuroSign", 8364, '\u{20ac}'),
         ("d", 100, '\u{64}'),
         ("dabovedot", 16784907, '\u{1e0b}'),
         ("dagger", 2801, '\u{2020}'),
@@ -1055,9 +1124,15 @@ pub fn build_char_keysym() -> CharKeySymMap {
     for (_, keysym, chr) in symbols {
         keysym_to_char.insert(keysym as u32, chr as u32);
     }
+
+    let mut char_to_name = HashMap::new();
+    for (name, _, chr) in symbols {
+        char_to_name.insert(chr as u32, name);
+    }
     CharKeySymMap {
         keysym_to_char,
         char_to_keysym,
+        char_to_name,
     }
 }
 
@@ -1,4 +1,5 @@
 pub mod connection;
+pub mod error;
 pub mod keysyms;
 pub mod platform_impl;
 pub mod simulate;
@@ -7,7 +7,7 @@ use winapi::{
             AttachThreadInput, GetForegroundWindow, GetKeyboardLayout, GetKeyboardState,
             GetWindowThreadProcessId, MapVirtualKeyW, SetKeyboardState, ToUnicode, MAPVK_VK_TO_VSC,
             VK_CONTROL, VK_DECIMAL, VK_LCONTROL, VK_LMENU, VK_LSHIFT, VK_LWIN, VK_MENU, VK_PACKET,
-            VK_RCONTROL, VK_RMENU, VK_RSHIFT, VK_RWIN, VK_SHIFT,
+            VK_RCONTROL, VK_RMENU, VK_RSHIFT, VK_RWIN, VK_SCROLL, VK_SHIFT,
         },
     },
 };
@@ -318,13 +318,16 @@ impl WinKeyboard {
             // (VK_MENU, Modifiers::ALT),
             (VK_LMENU, Modifiers::LEFT_ALT),
             (VK_RMENU, Modifiers::RIGHT_ALT),
-            (VK_LWIN, Modifiers::META),
-            (VK_RWIN, Modifiers::META),
+            (VK_LWIN, Modifiers::LEFT_META),
+            (VK_RWIN, Modifiers::RIGHT_META),
         ] {
             if self.is_pressed(vk_code) {
                 modifiers |= modifier;
             }
         }
+        if self.is_toggled(VK_SCROLL) {
+            modifiers |= Modifiers::SCROLL;
+        }
 
         modifiers
     }
@@ -346,6 +349,10 @@ impl WinKeyboard {
         (self.last_states.borrow()[vk_code as usize] & 0x80) != 0
     }
 
+    fn is_toggled(&self, vk_code: i32) -> bool {
+        (self.last_states.borrow()[vk_code as usize] & 0x01) != 0
+    }
+
     pub fn is_dead_key_leader(&mut self, mods: Modifiers, vk: u32) -> Option<char> {
         unsafe {
             self.update();
@@ -1,6 +1,7 @@
+use crate::error::Result;
 use crate::platform_impl::platform::connection::WinConnection;
-use crate::simulate::Simulate;
-use crate::types::{self, ServerMode, SimEvent};
+use crate::simulate::{Simulate, SimulatorHandle};
+use crate::types::{ServerMode, TypeOptions};
 use std::rc::Rc;
 
 pub struct WinSimulator {
@@ -14,37 +15,39 @@ impl WinSimulator {
 }
 
 impl Simulate for WinSimulator {
-    fn spawn_server(mode: ServerMode) -> anyhow::Result<std::thread::JoinHandle<()>> {
+    fn spawn_server(mode: ServerMode) -> Result<SimulatorHandle> {
         todo!()
     }
 
-    fn event_to_server(_simu_event: &types::SimEvent) -> anyhow::Result<()> {
+    fn simulate_keycode(&mut self, _keycode: u32, _press: bool) -> Result<()> {
         todo!()
     }
 
-    fn simulate_keycode(&mut self, _keycode: u32, _press: bool) {
+    fn simulate_keysym(&mut self, _keysym: u32, _press: bool) -> Result<()> {
         todo!()
     }
 
-    fn simulate_keysym(&mut self, _keysym: u32, _press: bool) {
+    fn simulate_char_without_modifiers(&mut self, _chr: char) -> Result<()> {
         todo!()
     }
 
-    fn simulate_char_without_modifiers(&mut self, _chr: char) {
+    fn simulate_phys(&mut self, _phys: crate::types::PhysKeyCode, _press: bool) -> Result<()> {
         todo!()
     }
 
-    fn simulate_phys(&mut self, _phys: crate::types::PhysKeyCode, _press: bool) {
+    fn simulate_key_event(&mut self, _key_event: &crate::types::KeyEvent) -> Result<()> {
         todo!()
     }
 
-    fn simulate_key_event(&mut self, _key_event: &crate::types::KeyEvent) {
+    fn simulate_server(&mut self, key_event: &crate::types::KeyEvent) -> Result<()> {
         todo!()
     }
 
-    fn simulate_server(&mut self, key_event: &crate::types::KeyEvent) {
+    fn release_modifiers(&mut self) -> Result<()> {
         todo!()
     }
 
-    fn release_modifiers(&mut self) -> anyhow::Result<()> {}
+    fn type_text(&mut self, _text: &str, _options: &TypeOptions) -> Result<()> {
+        todo!()
+    }
 }
@@ -0,0 +1,184 @@
+//! The simulator for tokio. The simulation runs in its own thread like
+//! `Simulate::spawn_server`, the futures resolve once the X server has
+//! processed the input.
+
+use super::connection::{MessageLoopObserver, XConnection};
+
+use crate::connection::ConnectionOps;
+use crate::error::{KeyboarderError, Result};
+use crate::simulate::SimulatorHandle;
+use crate::types::{KeyEvent, KeyboardEvent, ServerMode, SimEvent, TypeOptions};
+
+use std::collections::VecDeque;
+use std::sync::{mpsc, Arc, Mutex};
+use tokio::sync::{broadcast, oneshot};
+
+/// The keyboard events missed by a slow subscriber are dropped.
+const EVENTS_CAPACITY: usize = 64;
+
+type Replies = Arc<Mutex<VecDeque<oneshot::Sender<Result<()>>>>>;
+
+pub struct AsyncSimulator {
+    writer: Arc<Writer>,
+    events: broadcast::Sender<KeyboardEvent>,
+}
+
+impl AsyncSimulator {
+    pub fn spawn(mode: ServerMode) -> Result<Self> {
+        let replies = Replies::default();
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+
+        let mut observer = Observer {
+            replies: Arc::clone(&replies),
+            events: events.clone(),
+            keyboard_events: None,
+        };
+        let handle =
+            SimulatorHandle::spawn(move |mut read_fd| match XConnection::with_simulator(mode) {
+                Ok(conn) => {
+                    observer.keyboard_events = Some(conn.keyboard.subscribe());
+                    if let Err(err) = conn.run_message_loop_with(&mut read_fd, &mut observer) {
+                        log::error!("Failed to process message: {:?}", err);
+                    };
+                }
+                Err(err) => {
+                    log::error!(
+                        "Failed to init Connection, Please check env Display: {:?}",
+                        err
+                    )
+                }
+            })?;
+
+        Ok(Self {
+            writer: Arc::new(Writer {
+                handle,
+                order: Mutex::new(()),
+                replies,
+            }),
+            events,
+        })
+    }
+
+    /// Resolve once the event is simulated and processed by the X server.
+    pub async fn send(&self, event: SimEvent) -> Result<()> {
+        let (reply, done) = oneshot::channel();
+        // The pipe write blocks while the thread is busy.
+        let writer = Arc::clone(&self.writer);
+        tokio::task::spawn_blocking(move || writer.send(&event, reply))
+            .await
+            .map_err(|err| KeyboarderError::Other(err.into()))??;
+
+        done.await
+            .map_err(|_| KeyboarderError::Other(anyhow::anyhow!("The simulator thread stopped")))?
+    }
+
+    pub async fn simulate(&self, key_event: KeyEvent) -> Result<()> {
+        self.send(SimEvent::Simulate(key_event)).await
+    }
+
+    /// Resolve once the whole text is typed.
+    pub async fn type_text(&self, text: &str, options: TypeOptions) -> Result<()> {
+        self.send(SimEvent::TypeText {
+            text: text.to_owned(),
+            options,
+        })
+        .await
+    }
+
+    pub async fn release_keys(&self) -> Result<()> {
+        self.send(SimEvent::ReleaseKeys).await
+    }
+
+    /// The layout, keymap, modifiers and locks changes seen by the thread.
+    pub fn subscribe(&self) -> KeyboardEvents {
+        KeyboardEvents(self.events.subscribe())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.writer.handle.is_running()
+    }
+
+    /// Release the held keys and wait for the thread without blocking the
+    /// runtime, dropping the simulator blocks until the thread is stopped.
+    pub async fn shutdown(self) -> Result<()> {
+        let writer = self.writer;
+        tokio::task::spawn_blocking(move || writer.handle.shutdown())
+            .await
+            .map_err(|err| KeyboarderError::Other(err.into()))?
+    }
+}
+
+struct Writer {
+    handle: SimulatorHandle,
+    /// Held from queuing a reply to writing its event, they stay in order.
+    order: Mutex<()>,
+    /// One per event sent and not simulated yet, in the same order.
+    replies: Replies,
+}
+
+impl Writer {
+    fn send(&self, event: &SimEvent, reply: oneshot::Sender<Result<()>>) -> Result<()> {
+        let _order = self.order.lock().unwrap();
+        // The thread locks the replies too, it must not wait on the write.
+        self.replies.lock().unwrap().push_back(reply);
+        if let Err(err) = self.handle.send(event) {
+            // Nothing was queued since, the reply is the last one.
+            self.replies.lock().unwrap().pop_back();
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+/// A stream of `KeyboardEvent`.
+pub struct KeyboardEvents(broadcast::Receiver<KeyboardEvent>);
+
+impl KeyboardEvents {
+    /// None once the simulator is gone.
+    pub async fn next(&mut self) -> Option<KeyboardEvent> {
+        loop {
+            match self.0.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(num)) => {
+                    log::warn!("Missed {num} keyboard events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Lives in the simulator thread.
+struct Observer {
+    replies: Replies,
+    events: broadcast::Sender<KeyboardEvent>,
+    keyboard_events: Option<mpsc::Receiver<KeyboardEvent>>,
+}
+
+impl Observer {
+    fn forward_keyboard_events(&self) {
+        if let Some(keyboard_events) = &self.keyboard_events {
+            for event in keyboard_events.try_iter() {
+                // No subscriber is fine.
+                let _ = self.events.send(event);
+            }
+        }
+    }
+}
+
+impl MessageLoopObserver for Observer {
+    fn processed(&mut self, conn: &XConnection, result: Result<()>) {
+        let result = result.and_then(|_| Ok(conn.sync()?));
+        self.forward_keyboard_events();
+
+        // ExitThread is sent by the handle itself, without reply.
+        if let Some(reply) = self.replies.lock().unwrap().pop_front() {
+            // The caller may have dropped the future.
+            let _ = reply.send(result);
+        }
+    }
+
+    fn xcb_processed(&mut self, _conn: &XConnection) {
+        self.forward_keyboard_events();
+    }
+}
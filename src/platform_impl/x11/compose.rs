@@ -0,0 +1,134 @@
+//! Typing the chars missing from the keymap with the Compose sequences of the
+//! locale, e.g. Multi_key, equal, slash for '≠'.
+
+use super::keyboard::query_lc_ctype;
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use xkbcommon::xkb::{self, compose};
+
+/// Longer sequences are not worth it, the keycode is remapped instead.
+const MAX_SEQUENCE_LEN: usize = 6;
+
+/// The Compose table of a locale, from ~/.XCompose or the libX11 tables.
+#[derive(Clone)]
+pub struct ComposeTable {
+    table: compose::Table,
+}
+
+impl ComposeTable {
+    /// The table of the LC_CTYPE locale, the environment gives it when the
+    /// program hasn't called setlocale.
+    pub fn from_env(context: &xkb::Context) -> anyhow::Result<Self> {
+        let locale = query_lc_ctype()?;
+        let locale = match locale.to_str() {
+            Some("C") | Some("POSIX") => env_locale(),
+            _ => locale.to_owned(),
+        };
+        Self::new_from_locale(context, &locale)
+    }
+
+    pub fn new_from_locale(context: &xkb::Context, locale: &OsStr) -> anyhow::Result<Self> {
+        let table = compose::Table::new_from_locale(context, locale, compose::COMPILE_NO_FLAGS)
+            .map_err(|_| anyhow::anyhow!("No Compose table for the locale {:?}", locale))?;
+        Ok(Self { table })
+    }
+
+    /// Compile a table in the Compose file format.
+    pub fn new_from_string(
+        context: &xkb::Context,
+        table: &str,
+        locale: &str,
+    ) -> anyhow::Result<Self> {
+        let table = compose::Table::new_from_buffer(
+            context,
+            table,
+            locale,
+            compose::FORMAT_TEXT_V1,
+            compose::COMPILE_NO_FLAGS,
+        )
+        .map_err(|_| anyhow::anyhow!("Failed to compile the Compose table"))?;
+        Ok(Self { table })
+    }
+
+    /// The shortest sequence of each string the `keysyms` can compose.
+    pub fn shortest_sequences(&self, mut keysyms: Vec<xkb::Keysym>) -> ComposeSequences {
+        let mut state = compose::State::new(&self.table, compose::STATE_NO_FLAGS);
+        let mut sequences = HashMap::new();
+
+        keysyms.sort_unstable();
+        keysyms.dedup();
+        // The modifiers don't change the state, they are tried once.
+        let fed: Vec<xkb::Keysym> = keysyms
+            .iter()
+            .copied()
+            .filter(|&keysym| {
+                state.reset();
+                state.feed(keysym) == compose::FeedResult::Accepted
+            })
+            .collect();
+
+        // Breadth first, the prefixes being composed. The state can't be
+        // copied, the prefix is fed again after each keysym leaving it.
+        let mut prefixes = VecDeque::from([vec![]]);
+        while let Some(prefix) = prefixes.pop_front() {
+            for &keysym in &fed {
+                state.reset();
+                for &prev in &prefix {
+                    state.feed(prev);
+                }
+                state.feed(keysym);
+
+                match state.status() {
+                    compose::Status::Composing if prefix.len() + 1 < MAX_SEQUENCE_LEN => {
+                        prefixes.push_back([prefix.as_slice(), &[keysym]].concat())
+                    }
+                    compose::Status::Composed => {
+                        if let Some(utf8) = state.utf8() {
+                            sequences
+                                .entry(utf8)
+                                .or_insert_with(|| [prefix.as_slice(), &[keysym]].concat());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        ComposeSequences { keysyms, sequences }
+    }
+}
+
+/// The shortest Compose sequences of a layout, with the keysyms they were
+/// searched among.
+#[derive(Debug, Clone, Default)]
+pub struct ComposeSequences {
+    /// Sorted.
+    keysyms: Vec<xkb::Keysym>,
+    sequences: HashMap<String, Vec<xkb::Keysym>>,
+}
+
+impl ComposeSequences {
+    pub fn get(&self, chr: char) -> Option<&[xkb::Keysym]> {
+        self.sequences
+            .get(chr.encode_utf8(&mut [0; 4]))
+            .map(Vec::as_slice)
+    }
+
+    /// The sequences stay valid while the layout keeps all the keysyms, e.g.
+    /// when a spare keycode is remapped. `keysyms` must be sorted.
+    pub fn is_valid_for(&self, keysyms: &[xkb::Keysym]) -> bool {
+        self.keysyms
+            .iter()
+            .all(|keysym| keysyms.binary_search(keysym).is_ok())
+    }
+}
+
+/// The locale as libX11 reads it.
+fn env_locale() -> OsString {
+    ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .filter_map(std::env::var_os)
+        .find(|locale| !locale.is_empty())
+        .unwrap_or_else(|| "C".into())
+}
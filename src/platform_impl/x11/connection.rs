@@ -1,16 +1,33 @@
 use crate::{
     connection::ConnectionOps,
     platform_impl::Simulator,
-    simulate::Simulate,
-    types::{KeyEvent, SimEvent},
+    simulate::{FrameDecoder, Simulate},
+    types::{KeyEvent, KeyboardDevice, KeymapDescription, LayoutInfo, SimEvent, TypeOptions},
 };
 
-use super::keyboard::XKeyboard;
+use super::keyboard::{list_keyboard_devices, XKeyboard};
 
 use anyhow::{anyhow, Context};
 use filedescriptor::FileDescriptor;
 use mio::{unix::SourceFd, Events, Interest, Poll, Token};
-use std::{cell::RefCell, io::Read, os::unix::prelude::AsRawFd};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::Read,
+    os::unix::prelude::{AsRawFd, RawFd},
+};
+
+/// Watches `XConnection::run_message_loop_with`.
+pub trait MessageLoopObserver {
+    /// An event of the pipe was simulated, they come in the order they were
+    /// sent.
+    fn processed(&mut self, _conn: &XConnection, _result: crate::error::Result<()>) {}
+
+    /// The queued X events were processed.
+    fn xcb_processed(&mut self, _conn: &XConnection) {}
+}
+
+impl MessageLoopObserver for () {}
 
 pub struct XConnection {
     pub conn: xcb::Connection,
@@ -18,12 +35,46 @@ pub struct XConnection {
     pub root: xcb::x::Window,
     pub keyboard: XKeyboard,
     pub simulator: RefCell<Option<Simulator>>,
+    /// Submitted and not simulated yet.
+    pending_events: RefCell<VecDeque<SimEvent>>,
+}
+
+impl Drop for XConnection {
+    fn drop(&mut self) {
+        // The simulator can't reach the connection anymore.
+        if let Some(mut simulator) = self.simulator.get_mut().take() {
+            simulator.cleanup(self);
+        }
+        if let Err(err) = self.keyboard.restore_auto_repeat(&self.conn) {
+            log::error!("{err:#}");
+        }
+    }
+}
+
+impl AsRawFd for XConnection {
+    /// Readable when the X server sent events, then call `dispatch`.
+    fn as_raw_fd(&self) -> RawFd {
+        self.conn.as_raw_fd()
+    }
 }
 
 impl XConnection {
     pub fn create_new() -> anyhow::Result<XConnection> {
-        let (conn, screen_num) =
-            xcb::Connection::connect_with_xlib_display_and_extensions(&[xcb::Extension::Xkb], &[])?;
+        Self::create_with(XKeyboard::new)
+    }
+
+    /// Observe and simulate with the keyboard device `device_id`.
+    pub fn create_for_device(device_id: u16) -> anyhow::Result<XConnection> {
+        Self::create_with(|conn| XKeyboard::new_for_device(conn, device_id))
+    }
+
+    fn create_with(
+        new_keyboard: impl FnOnce(&xcb::Connection) -> anyhow::Result<XKeyboard>,
+    ) -> anyhow::Result<XConnection> {
+        let (conn, screen_num) = xcb::Connection::connect_with_xlib_display_and_extensions(
+            &[xcb::Extension::Xkb],
+            &[xcb::Extension::Input],
+        )?;
         let screen = conn
             .get_setup()
             .roots()
@@ -31,7 +82,7 @@ impl XConnection {
             .ok_or_else(|| anyhow!("no screen?"))?;
         let root = screen.root();
 
-        let keyboard = XKeyboard::new(&conn)?;
+        let keyboard = new_keyboard(&conn)?;
 
         let conn = XConnection {
             conn,
@@ -39,15 +90,39 @@ impl XConnection {
             root,
             keyboard,
             simulator: RefCell::new(None),
+            pending_events: RefCell::new(VecDeque::new()),
         };
 
         anyhow::Ok(conn)
     }
 
+    pub fn list_keyboard_devices(&self) -> anyhow::Result<Vec<KeyboardDevice>> {
+        list_keyboard_devices(&self.conn)
+    }
+
     pub fn conn(&self) -> &xcb::Connection {
         &self.conn
     }
+
+    pub fn get_layout_info(&self) -> anyhow::Result<LayoutInfo> {
+        self.keyboard.get_layout_info(&self.conn, self.root)
+    }
+
+    pub fn describe_keymap(&self) -> anyhow::Result<KeymapDescription> {
+        self.keyboard.describe_keymap(&self.conn, self.root)
+    }
+
     pub fn run_message_loop(&self, read_fd: &mut FileDescriptor) -> anyhow::Result<()> {
+        self.run_message_loop_with(read_fd, &mut ())
+    }
+
+    /// Simulate the events read from `read_fd` until ExitThread or until the
+    /// writer is closed, `observer` is told about every step.
+    pub fn run_message_loop_with(
+        &self,
+        read_fd: &mut FileDescriptor,
+        observer: &mut impl MessageLoopObserver,
+    ) -> anyhow::Result<()> {
         const TOK_SIMULATE: mio::Token = Token(0xffff_fffc);
         const TOK_XKB: mio::Token = Token(0xffff_fffb);
         let mut poll = Poll::new()?;
@@ -64,46 +139,111 @@ impl XConnection {
             Interest::READABLE,
         )?;
 
+        let mut decoder = FrameDecoder::default();
+        let mut buf = vec![0; 4096];
         loop {
             poll.poll(&mut events, None)
                 .map_err(|err| anyhow::anyhow!("polling for events: {:?}", err))?;
-            for event in &events {
-                match event.token() {
-                    TOK_SIMULATE => {
-                        let mut buf = vec![0; 64];
-                        #[allow(clippy::unused_io_amount)]
-                        let num = read_fd.read(&mut buf)?;
-                        anyhow::ensure!(num != buf.len(), "buf is too small");
-
-                        match SimEvent::try_from(buf)? {
-                            SimEvent::ExitThread => {
-                                log::info!("Exit simulate thread");
-                                // FIXME: release by drop
-                                if let Some(simulator) = self.simulator.borrow_mut().as_mut() {
-                                    simulator.release_modifiers()?;
-                                }
-                                break;
-                            }
-                            SimEvent::Simulate(key_event) => {
-                                self.process_server_event_log(&key_event)
-                            }
-                            SimEvent::ReleaseKeys => {
-                                if let Some(simulator) = self.simulator.borrow_mut().as_mut() {
-                                    simulator.release_modifiers()?;
-                                }
-                            }
-                        }
-                    }
-                    TOK_XKB => {
-                        self.process_queued_xcb_log();
+
+            let mut closed = false;
+            if events.iter().any(|event| event.token() == TOK_SIMULATE) {
+                // Drain the pipe, the messages may be split or merged.
+                closed = loop {
+                    match read_fd.read(&mut buf) {
+                        Ok(0) => break true,
+                        Ok(num) => decoder.push(&buf[..num]),
+                        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break false,
+                        Err(err) => return Err(err.into()),
                     }
-                    _ => {}
+                };
+
+                while let Some(sim_event) = decoder.next_event()? {
+                    self.submit(sim_event);
+                }
+            }
+
+            // TOK_XKB only needs the dispatch.
+            if self.dispatch_with(observer)? {
+                return Ok(());
+            }
+            if closed {
+                log::info!("The simulator handle is gone");
+                return Ok(self.release_keys()?);
+            }
+        }
+    }
+
+    /// Queue `event` for the next `dispatch`, the simulator is the one set by
+    /// `ConnectionOps::with_simulator`.
+    pub fn submit(&self, event: SimEvent) {
+        self.pending_events.borrow_mut().push_back(event);
+    }
+
+    pub fn has_pending_events(&self) -> bool {
+        !self.pending_events.borrow().is_empty()
+    }
+
+    /// Process the queued X events and simulate the submitted events without
+    /// blocking, for event loops watching `as_raw_fd`. Returns true once
+    /// ExitThread is simulated, the events after it are dropped.
+    pub fn dispatch(&self) -> anyhow::Result<bool> {
+        self.dispatch_with(&mut ())
+    }
+
+    pub fn dispatch_with(&self, observer: &mut impl MessageLoopObserver) -> anyhow::Result<bool> {
+        self.process_queued_xcb_log();
+        observer.xcb_processed(self);
+
+        loop {
+            // Not borrowed while simulating, the simulation may submit.
+            let sim_event = self.pending_events.borrow_mut().pop_front();
+            let Some(sim_event) = sim_event else {
+                break;
+            };
+
+            let exit = sim_event == SimEvent::ExitThread;
+            let res = match sim_event {
+                SimEvent::ExitThread => {
+                    log::info!("Exit simulate thread");
+                    self.release_keys().and_then(|_| self.restore_keymap())
                 }
+                SimEvent::Simulate(key_event) => self.process_server_event(&key_event),
+                SimEvent::ReleaseKeys => self.release_keys(),
+                SimEvent::TypeText { text, options } => self.type_text(&text, &options),
+            };
+            if let Err(err) = &res {
+                log::error!("{err:#}");
+            }
+            observer.processed(self, res);
+            if exit {
+                self.pending_events.borrow_mut().clear();
+                return Ok(true);
             }
         }
+
+        // Waiting for the replies queued the events arriving meanwhile, the
+        // fd won't wake the event loop for them.
+        self.process_queued_xcb_log();
+        observer.xcb_processed(self);
+
+        self.conn.flush().context("flushing pending requests")?;
+        Ok(false)
+    }
+
+    /// A round trip: all the requests sent before are processed by the server.
+    pub fn sync(&self) -> anyhow::Result<()> {
+        self.send_and_wait_request(&xcb::x::GetInputFocus {})?;
+        Ok(())
+    }
+
+    fn release_keys(&self) -> crate::error::Result<()> {
+        if let Some(simulator) = self.simulator.borrow_mut().as_mut() {
+            simulator.release_keys()?;
+        }
+        Ok(())
     }
 
-    pub(crate) fn send_request_no_reply<R>(&self, req: &R) -> anyhow::Result<()>
+    pub(crate) fn send_request_checked<R>(&self, req: &R) -> anyhow::Result<()>
     where
         R: xcb::RequestWithoutReply + std::fmt::Debug,
     {
@@ -112,16 +252,6 @@ impl XConnection {
             .with_context(|| format!("{req:#?}"))
     }
 
-    pub(crate) fn send_request_no_reply_log<R>(&self, req: &R)
-    where
-        R: xcb::RequestWithoutReply + std::fmt::Debug,
-    {
-        if let Err(err) = self.send_request_no_reply(req) {
-            log::error!("{err:#}");
-        }
-    }
-
-    #[allow(dead_code)]
     pub(crate) fn send_and_wait_request<R>(
         &self,
         req: &R,
@@ -136,28 +266,38 @@ impl XConnection {
             .with_context(|| format!("{req:#?}"))
     }
 
-    fn process_server_event_log(&self, key_event: &KeyEvent) {
-        if let Err(err) = self.process_server_event(key_event) {
+    fn process_queued_xcb_log(&self) {
+        if let Err(err) = self.process_queued_xcb() {
             log::error!("{err:#}");
         }
     }
 
-    fn process_queued_xcb_log(&self) {
-        if let Err(err) = self.process_queued_xcb() {
-            log::error!("{err:#}");
+    fn restore_keymap(&self) -> crate::error::Result<()> {
+        if let Some(simulator) = self.simulator.borrow_mut().as_mut() {
+            simulator.restore_keymap()?;
+        }
+        Ok(())
+    }
+
+    fn type_text(&self, text: &str, options: &TypeOptions) -> crate::error::Result<()> {
+        if let Some(simulator) = self.simulator.borrow_mut().as_mut() {
+            simulator.type_text(text, options)?;
         }
+
+        Ok(())
     }
 
-    fn process_server_event(&self, key_event: &KeyEvent) -> anyhow::Result<()> {
+    fn process_server_event(&self, key_event: &KeyEvent) -> crate::error::Result<()> {
         if let Some(simulator) = self.simulator.borrow_mut().as_mut() {
-            simulator.simulate_server(key_event);
+            simulator.simulate_server(key_event)?;
         }
 
         Ok(())
     }
 
-    fn process_queued_xcb(&self) -> anyhow::Result<()> {
-        if let Some(event) = self
+    /// Process all the queued events without blocking.
+    pub(crate) fn process_queued_xcb(&self) -> anyhow::Result<()> {
+        while let Some(event) = self
             .conn
             .poll_for_event()
             .context("X11 connection is broken")?
@@ -1,20 +1,26 @@
 use crate::{
-    keysyms::CHAR_KEYSYM_MAP,
+    keysyms::{self, CHAR_KEYSYM_MAP},
     platform_impl::platform::keycodes::build_phys_keycode_map,
-    types::{GroupIndex, KeyCode, KeyEvent},
+    types::{
+        AutoRepeat, Indicator, KeyDescription, KeyboardDevice, KeyboardDeviceKind,
+        KeymapDescription, LevelDescription, ModifierState,
+    },
+    types::{GroupIndex, KeyCode, KeyEvent, KeyboardEvent, LayoutGroup, LayoutInfo, RuleNames},
     types::{Modifiers, PhysKeyCode},
 };
+use anyhow::Context;
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::HashMap,
     ffi::{CStr, OsStr},
     os::unix::prelude::OsStrExt,
+    sync::mpsc::{self, Receiver, Sender},
 };
 
+use super::compose::{ComposeSequences, ComposeTable};
+use super::modmap::ModifierMap;
 use xkbcommon::xkb::{self};
 
-pub const MOD_NAME_ISO_LEVEL3_SHIFT: &str = "Mod5";
-
 pub fn query_lc_ctype() -> anyhow::Result<&'static OsStr> {
     let ptr = unsafe { libc::setlocale(libc::LC_CTYPE, std::ptr::null()) };
     anyhow::ensure!(!ptr.is_null(), "failed to query locale");
@@ -23,74 +29,330 @@ pub fn query_lc_ctype() -> anyhow::Result<&'static OsStr> {
     Ok(OsStr::from_bytes(cstr.to_bytes()))
 }
 
-#[inline]
-pub fn level_to_modifiers(level: u32) -> Modifiers {
-    match level {
-        0 => Modifiers::NONE,
-        1 => Modifiers::SHIFT,
-        2 => Modifiers::ALT_GR,
-        3 => Modifiers::SHIFT | Modifiers::ALT_GR,
-        _ => Modifiers::NONE,
+extern "C" {
+    // Since libxkbcommon 1.0, not wrapped by the xkbcommon crate.
+    fn xkb_keymap_key_get_mods_for_level(
+        keymap: *mut xkb::ffi::xkb_keymap,
+        key: xkb::Keycode,
+        layout: xkb::LayoutIndex,
+        level: xkb::LevelIndex,
+        masks_out: *mut xkb::ModMask,
+        masks_size: usize,
+    ) -> usize;
+}
+
+/// Get the modifier masks which make the key type of `keycode` select `level`.
+pub fn key_get_mods_for_level(
+    keymap: &xkb::Keymap,
+    keycode: xkb::Keycode,
+    layout: xkb::LayoutIndex,
+    level: xkb::LevelIndex,
+) -> Vec<xkb::ModMask> {
+    let mut masks = [0; 16];
+    let num = unsafe {
+        xkb_keymap_key_get_mods_for_level(
+            keymap.get_raw_ptr(),
+            keycode,
+            layout,
+            level,
+            masks.as_mut_ptr(),
+            masks.len(),
+        )
+    };
+    masks[..num].to_vec()
+}
+
+/// The place of a keysym in the keymap, and the exact modifiers the key type
+/// needs to reach it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeysymPlan {
+    pub keycode: xkb::Keycode,
+    pub layout: xkb::LayoutIndex,
+    pub level: xkb::LevelIndex,
+    pub modifiers: Modifiers,
+    /// The locks to set while typing the keysym, e.g. NUM for the keypad
+    /// digits, None keeps the locks of the user.
+    pub locks: Option<Modifiers>,
+}
+
+impl KeysymPlan {
+    pub fn key_event(&self, press: bool) -> KeyEvent {
+        KeyEvent {
+            key: KeyCode::RawCode(self.keycode),
+            press,
+            modifiers: self.modifiers,
+            raw_event: None,
+        }
+    }
+
+    /// Press and release the key, the modifiers must be held before.
+    pub fn key_events(&self) -> Vec<KeyEvent> {
+        vec![self.key_event(true), self.key_event(false)]
+    }
+}
+
+/// The held modifiers which may select a level, tried when the locks must
+/// stay as they are.
+const LEVEL_MODIFIERS: [Modifiers; 3] = [Modifiers::SHIFT, Modifiers::ALT_GR, Modifiers::LEVEL5];
+
+/// Choose the modifiers to reach `level`: locks are only used if there is
+/// no other way, then the fewer modifiers the better.
+fn modifiers_for_level(
+    keymap: &xkb::Keymap,
+    modifier_map: &ModifierMap,
+    keycode: xkb::Keycode,
+    layout: xkb::LayoutIndex,
+    level: xkb::LevelIndex,
+) -> Option<Modifiers> {
+    key_get_mods_for_level(keymap, keycode, layout, level)
+        .into_iter()
+        .filter_map(|mask| modifier_map.try_to_modifiers(mask))
+        .min_by_key(|modifiers| {
+            (
+                modifiers.intersects(Modifiers::LOCKS),
+                modifiers.bits().count_ones(),
+            )
+        })
+}
+
+/// The other keysyms of the char of `keysym`, e.g. U20AC for EuroSign:
+/// `keysyms::char_to_keysym` may give either form.
+fn keysym_aliases(keysym: xkb::Keysym) -> Vec<xkb::Keysym> {
+    let chr = xkb::keysym_to_utf32(keysym);
+    if chr < 0x100 {
+        return vec![];
     }
+    let unicode = chr | 0x0100_0000;
+    let legacy = CHAR_KEYSYM_MAP.char_to_keysym.get(&chr).copied();
+    [Some(unicode), legacy]
+        .into_iter()
+        .flatten()
+        .filter(|&alias| alias != keysym)
+        .collect()
 }
 
-pub fn build_keysym_event_map(
+/// Both keysyms type the same char.
+fn is_same_keysym(a: xkb::Keysym, b: xkb::Keysym) -> bool {
+    a == b || keysym_aliases(a).contains(&b)
+}
+
+fn describe_levels(
     keymap: &xkb::Keymap,
-    min_keycode: u32,
-    max_keycode: u32,
-    layout: u32,
-) -> HashMap<u32, KeyEvent> {
-    let mut map: HashMap<u32, KeyEvent> = HashMap::new();
-
-    // todo
-    for keycode in min_keycode..=max_keycode {
-        let num_level = keymap.num_levels_for_key(keycode, layout);
-        for level in (0..num_level).rev() {
+    modifier_map: &ModifierMap,
+    keycode: xkb::Keycode,
+    layout: xkb::LayoutIndex,
+) -> Vec<LevelDescription> {
+    (0..keymap.num_levels_for_key(keycode, layout))
+        .map(|level| {
             let keysyms = keymap.key_get_syms_by_level(keycode, layout, level);
-            if keysyms.is_empty() {
-                continue;
+            LevelDescription {
+                level,
+                keysyms: keysyms.to_vec(),
+                keysym_names: keysyms
+                    .iter()
+                    .map(|&keysym| xkb::keysym_get_name(keysym))
+                    .collect(),
+                chars: keysyms
+                    .iter()
+                    .filter_map(|&keysym| char::from_u32(xkb::keysym_to_utf32(keysym)))
+                    .filter(|&chr| chr != '\0')
+                    .collect(),
+                modifiers: modifiers_for_level(keymap, modifier_map, keycode, layout, level),
+            }
+        })
+        .collect()
+}
+
+/// Map every keysym of the keymap to all the (keycode, layout, level) producing it.
+///
+/// The plans of a keysym are sorted by layout, then by preference.
+pub fn build_keysym_plan_map(
+    keymap: &xkb::Keymap,
+    modifier_map: &ModifierMap,
+) -> HashMap<xkb::Keysym, Vec<KeysymPlan>> {
+    let mut map: HashMap<xkb::Keysym, Vec<KeysymPlan>> = HashMap::new();
+
+    // The X11 keycodes fit in a byte, e.g. KEY_EURO (443) can't be sent.
+    for keycode in keymap.min_keycode()..=keymap.max_keycode().min(255) {
+        for layout in 0..keymap.num_layouts_for_key(keycode) {
+            for level in 0..keymap.num_levels_for_key(keycode, layout) {
+                let keysyms = keymap.key_get_syms_by_level(keycode, layout, level);
+                // A level producing several keysyms can't type one of them alone.
+                if keysyms.len() != 1 {
+                    continue;
+                }
+                if let Some(modifiers) =
+                    modifiers_for_level(keymap, modifier_map, keycode, layout, level)
+                {
+                    let plan = KeysymPlan {
+                        keycode,
+                        layout,
+                        level,
+                        modifiers,
+                        locks: None,
+                    };
+                    for keysym in std::iter::once(keysyms[0]).chain(keysym_aliases(keysyms[0])) {
+                        map.entry(keysym).or_default().push(plan);
+                    }
+                }
             }
-            let keysym = keysyms[0];
-            let key_event = KeyEvent {
-                key: KeyCode::RawCode(keycode),
-                press: false,
-                modifiers: level_to_modifiers(level),
-                raw_event: None,
-            };
-            map.insert(keysym, key_event);
         }
     }
 
+    for plans in map.values_mut() {
+        plans.sort_by_key(|plan| {
+            (
+                plan.layout,
+                plan.modifiers.intersects(Modifiers::LOCKS),
+                plan.modifiers.bits().count_ones(),
+                plan.level,
+                plan.keycode,
+            )
+        });
+    }
+
     map
 }
 
+/// The real modifiers held by pressing each key of the keymap, for the keys
+/// setting some.
+pub fn build_modifier_keycodes(keymap: &xkb::Keymap) -> Vec<(xkb::Keycode, xkb::ModMask)> {
+    (keymap.min_keycode()..=keymap.max_keycode().min(255))
+        .filter_map(|keycode| {
+            let mut state = xkb::State::new(keymap);
+            state.update_key(keycode, xkb::KeyDirection::Down);
+            // The virtual modifiers are also set on their real ones.
+            let mask = state.serialize_mods(xkb::STATE_MODS_DEPRESSED) & 0xff;
+            (mask != 0).then_some((keycode, mask))
+        })
+        .collect()
+}
+
 pub struct XKeyboard {
     phys_code_map: RefCell<HashMap<PhysKeyCode, xkb::Keycode>>,
     code_phys_map: RefCell<HashMap<xkb::Keycode, PhysKeyCode>>,
     pub keysym_keycode_map: RefCell<HashMap<xkb::Keysym, xkb::Keycode>>,
     pub char_keysym: RefCell<HashMap<xkb::Keysym, xkb::Keycode>>,
-    keysym_event_map: RefCell<HashMap<u32, KeyEvent>>,
+    keysym_plan_map: RefCell<HashMap<xkb::Keysym, Vec<KeysymPlan>>>,
+    modifier_keycodes: RefCell<Vec<(xkb::Keycode, xkb::ModMask)>>,
     pub unused_keycodes: RefCell<Vec<xkb::Keycode>>,
     pub state: RefCell<xkb::State>,
+    modifier_state: Cell<ModifierState>,
+    modifier_map: Cell<ModifierMap>,
+    /// One bit per indicator index of the keymap.
+    indicator_state: Cell<u32>,
+    /// Saved by the first change, restored when the connection is dropped.
+    original_auto_repeat: Cell<Option<AutoRepeat>>,
     pub keymap: RefCell<xkb::Keymap>,
-    device_id: u8,
+    device_id: Cell<u8>,
+    /// Follow the core keyboard when the device changes.
+    core_device: bool,
     group_index: RefCell<GroupIndex>,
     context: xkb::Context,
+    compose_table: RefCell<Option<ComposeTable>>,
+    /// The shortest Compose sequences of each layout, built on first use.
+    compose_sequences: RefCell<HashMap<xkb::LayoutIndex, ComposeSequences>>,
+    /// The keymap changed since the sequences were checked.
+    compose_stale: Cell<bool>,
+    subscribers: RefCell<Vec<Sender<KeyboardEvent>>>,
 }
 
 impl XKeyboard {
     pub fn new(connection: &xcb::Connection) -> anyhow::Result<Self> {
-        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
         let device_id = xkb::x11::get_core_keyboard_device_id(connection);
         anyhow::ensure!(device_id != -1, "Couldn't find core keyboard device");
 
+        let mut keyboard = Self::from_device(connection, device_id as _)?;
+        keyboard.core_device = true;
+
+        Ok(keyboard)
+    }
+
+    /// See `list_keyboard_devices` for the ids, the other devices are refused.
+    pub fn new_for_device(connection: &xcb::Connection, device_id: u16) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            list_keyboard_devices(connection)?
+                .iter()
+                .any(|device| device.id == device_id),
+            "Device {device_id} is not a keyboard"
+        );
+        let device_id = u8::try_from(device_id).context("XKB device ids fit in a byte")?;
+        Self::from_device(connection, device_id)
+    }
+
+    fn from_device(connection: &xcb::Connection, device_id: u8) -> anyhow::Result<Self> {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
         let keymap = xkb::x11::keymap_new_from_device(
             &context,
             connection,
-            device_id,
+            device_id.into(),
             xkb::KEYMAP_COMPILE_NO_FLAGS,
         );
-        let state = xkb::x11::state_new_from_device(&keymap, connection, device_id);
+        anyhow::ensure!(
+            !keymap.get_raw_ptr().is_null(),
+            "Couldn't get keymap of device {device_id}"
+        );
+        let state = xkb::x11::state_new_from_device(&keymap, connection, device_id.into());
+
+        select_events(connection, device_id)?;
+
+        let modifier_map = ModifierMap::query(connection, device_id, &keymap)?;
+        let compose_table = ComposeTable::from_env(&context)
+            .map_err(|err| log::debug!("Compose sequences disabled: {err:#}"))
+            .ok();
+        let keyboard = Self::from_keymap(context, keymap, state, modifier_map, device_id);
+        keyboard.set_compose_table(compose_table);
+        keyboard.update_indicator_state(connection)?;
+
+        Ok(keyboard)
+    }
+
+    /// Compile the keymap from RMLVO names without X server, empty fields
+    /// use the system defaults.
+    ///
+    /// The keyboard can plan chars but not simulate them, its device id is 0.
+    pub fn new_from_names(names: &RuleNames) -> anyhow::Result<Self> {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkb::Keymap::new_from_names(
+            &context,
+            &names.rules,
+            &names.model,
+            &names.layouts.join(","),
+            &names.variants.join(","),
+            Some(names.options.join(",")),
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .ok_or_else(|| anyhow::anyhow!("Failed to compile keymap from {:?}", names))?;
+        let state = xkb::State::new(&keymap);
+        let modifier_map = ModifierMap::from_keymap(&keymap);
+
+        Ok(Self::from_keymap(context, keymap, state, modifier_map, 0))
+    }
+
+    /// Compile a keymap in the xkbcommon text format without X server, see
+    /// `get_keymap_string`.
+    pub fn new_from_string(keymap: &str) -> anyhow::Result<Self> {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkb::Keymap::new_from_string(
+            &context,
+            keymap.to_owned(),
+            xkb::KEYMAP_FORMAT_TEXT_V1,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .ok_or_else(|| anyhow::anyhow!("Failed to compile keymap from string"))?;
+        let state = xkb::State::new(&keymap);
+        let modifier_map = ModifierMap::from_keymap(&keymap);
+
+        Ok(Self::from_keymap(context, keymap, state, modifier_map, 0))
+    }
+
+    fn from_keymap(
+        context: xkb::Context,
+        keymap: xkb::Keymap,
+        state: xkb::State,
+        modifier_map: ModifierMap,
+        device_id: u8,
+    ) -> Self {
         let (code_phys_map, phys_code_map) = build_phys_keycode_map(&keymap);
         let mut keysym_keycode_map = HashMap::new();
         // FIXME: update when switch keyboard
@@ -120,75 +382,400 @@ impl XKeyboard {
         }
 
         let group_index = get_active_group_index(&state, &keymap);
-        {
-            // Set the keyboard events that need to be monitored.
-            let map_parts = xcb::xkb::MapPart::KEY_TYPES
-                | xcb::xkb::MapPart::KEY_SYMS
-                | xcb::xkb::MapPart::MODIFIER_MAP
-                | xcb::xkb::MapPart::EXPLICIT_COMPONENTS
-                | xcb::xkb::MapPart::KEY_ACTIONS
-                | xcb::xkb::MapPart::KEY_BEHAVIORS
-                | xcb::xkb::MapPart::VIRTUAL_MODS
-                | xcb::xkb::MapPart::VIRTUAL_MOD_MAP;
-
-            let events = xcb::xkb::EventType::NEW_KEYBOARD_NOTIFY
-                | xcb::xkb::EventType::MAP_NOTIFY
-                | xcb::xkb::EventType::STATE_NOTIFY;
-            connection.check_request(connection.send_request_checked(&xcb::xkb::SelectEvents {
-                device_spec: device_id as u16,
-                affect_which: events,
-                clear: xcb::xkb::EventType::empty(),
-                select_all: events,
-                affect_map: map_parts,
-                map: map_parts,
-                details: &[],
-            }))?;
-        }
+        let keysym_plan_map = build_keysym_plan_map(&keymap, &modifier_map);
+        let modifier_state = modifier_state_from(&state, &modifier_map);
+        let indicator_state = (0..keymap.num_leds())
+            .filter(|&idx| state.led_index_is_active(idx))
+            .fold(0, |bits, idx| bits | (1 << idx));
 
-        let keysym_event_map: HashMap<u32, KeyEvent> =
-            build_keysym_event_map(&keymap, min_keycode, max_keycode, group_index.into());
-
-        Ok(Self {
+        Self {
             phys_code_map: RefCell::new(phys_code_map),
             code_phys_map: RefCell::new(code_phys_map),
             keysym_keycode_map: RefCell::new(keysym_keycode_map),
             char_keysym: RefCell::new(char_keysym),
-            keysym_event_map: RefCell::new(keysym_event_map),
+            keysym_plan_map: RefCell::new(keysym_plan_map),
+            modifier_keycodes: RefCell::new(build_modifier_keycodes(&keymap)),
             unused_keycodes: RefCell::new(unused_keycodes),
             state: RefCell::new(state),
+            modifier_state: Cell::new(modifier_state),
+            modifier_map: Cell::new(modifier_map),
+            indicator_state: Cell::new(indicator_state),
+            original_auto_repeat: Cell::new(None),
             keymap: RefCell::new(keymap),
-            device_id: device_id as _,
+            device_id: Cell::new(device_id),
+            core_device: false,
             group_index: RefCell::new(group_index),
             context,
-        })
+            compose_table: RefCell::new(None),
+            compose_sequences: RefCell::new(HashMap::new()),
+            compose_stale: Cell::new(false),
+            subscribers: RefCell::new(vec![]),
+        }
     }
 
     /// https://stackoverflow.com/questions/69656145/how-does-modifiersas-in-xmodmap-work-under-linux-operating-system
     /// Use xmodmap -pm to get meaning of modifier
     ///
-    /// # Safety
-    ///
-    /// Warning: Can't use it in simulate, fake input will not
-    pub unsafe fn get_current_modifiers(&self) -> Modifiers {
-        let mut res = Modifiers::default();
-        for (mod_name, modifier) in [
-            (xkb::MOD_NAME_SHIFT, Modifiers::SHIFT),
-            (xkb::MOD_NAME_CTRL, Modifiers::CTRL),
-            (xkb::MOD_NAME_ALT, Modifiers::ALT),
-            (xkb::MOD_NAME_LOGO, Modifiers::META),
-            (xkb::MOD_NAME_CAPS, Modifiers::CAPS),
-            (xkb::MOD_NAME_NUM, Modifiers::NUM),
-            (MOD_NAME_ISO_LEVEL3_SHIFT, Modifiers::ALT_GR),
-        ] {
-            if self.mod_is_active(mod_name) {
-                res |= modifier;
-            }
+    /// Updated by the StateNotify passed to `process_xkb_event`.
+    pub fn get_current_modifiers(&self) -> Modifiers {
+        self.modifier_state.get().effective()
+    }
+
+    pub fn get_modifier_state(&self) -> ModifierState {
+        self.modifier_state.get()
+    }
+
+    /// What the real modifiers stand for, updated with the keymap.
+    pub fn get_modifier_map(&self) -> ModifierMap {
+        self.modifier_map.get()
+    }
+
+    /// The key holding `modifier` in this keymap. `key`, the key of the evdev
+    /// keymap, is kept if it holds the same real modifiers, else another key
+    /// holding them is used, e.g. Menu when it is the AltGr of the keymap.
+    pub fn get_modifier_key(&self, modifier: Modifiers, key: KeyCode) -> KeyCode {
+        let mask = self
+            .get_modifier_map()
+            .to_mod_mask(modifier - Modifiers::LOCKS);
+        if mask == 0 {
+            return key;
+        }
+        let keycode = match key {
+            KeyCode::Physical(phys) => self.get_keycode_by_phys(phys),
+            KeyCode::KeySym(keysym) => self.get_keycode_by_keysym(keysym),
+            KeyCode::RawCode(keycode) => Some(keycode),
+            _ => None,
+        };
+
+        let modifier_keycodes = self.modifier_keycodes.borrow();
+        let holding = |keycode: &xkb::Keycode| {
+            modifier_keycodes
+                .iter()
+                .any(|&(modifier_keycode, keycode_mask)| {
+                    modifier_keycode == *keycode && keycode_mask == mask
+                })
+        };
+        if keycode.as_ref().is_some_and(holding) {
+            return key;
+        }
+        // The keys of a keyboard first, not the virtual ones like <LVL3>.
+        modifier_keycodes
+            .iter()
+            .filter(|&&(_, keycode_mask)| keycode_mask == mask)
+            .min_by_key(|&&(keycode, _)| self.get_phys_by_keycode(keycode).is_none())
+            .map_or(key, |&(keycode, _)| KeyCode::RawCode(keycode))
+    }
+
+    /// Get the named indicators of the keymap.
+    pub fn get_indicators(&self) -> Vec<Indicator> {
+        let keymap = self.keymap.borrow();
+        let indicator_state = self.indicator_state.get();
+
+        (0..keymap.num_leds())
+            .filter(|&index| !keymap.led_get_name(index).is_empty())
+            .map(|index| Indicator {
+                index,
+                name: keymap.led_get_name(index).to_owned(),
+                active: indicator_state & (1 << index) != 0,
+            })
+            .collect()
+    }
+
+    /// e.g. `xkb::LED_NAME_SCROLL`
+    pub fn is_indicator_active(&self, name: &str) -> bool {
+        let index = self.keymap.borrow().led_get_index(name);
+        index != xkb::LED_INVALID && self.indicator_state.get() & (1 << index) != 0
+    }
+
+    /// Ask the server, IndicatorStateNotify keep it updated afterwards.
+    pub fn update_indicator_state(&self, connection: &xcb::Connection) -> anyhow::Result<()> {
+        let cookie = connection.send_request(&xcb::xkb::GetIndicatorState {
+            device_spec: self.device_id.get().into(),
+        });
+        self.indicator_state
+            .set(connection.wait_for_reply(cookie)?.state());
+        Ok(())
+    }
+
+    /// Lock exactly the CAPS, NUM and SCROLL of `locks` through XKB, instead
+    /// of tapping their keys.
+    pub fn set_locks(&self, connection: &xcb::Connection, locks: Modifiers) -> anyhow::Result<()> {
+        let modifier_map = self.get_modifier_map();
+        let affect = modifier_map.to_mod_mask(Modifiers::LOCKS);
+        let mod_locks = modifier_map.to_mod_mask(locks) & affect;
+
+        connection.send_and_check_request(&xcb::xkb::LatchLockState {
+            device_spec: self.device_id.get().into(),
+            affect_mod_locks: xcb::x::ModMask::from_bits_truncate(affect),
+            mod_locks: xcb::x::ModMask::from_bits_truncate(mod_locks),
+            lock_group: false,
+            group_lock: xcb::xkb::Group::N1,
+            affect_mod_latches: xcb::x::ModMask::empty(),
+            latch_group: false,
+            group_latch: 0,
+        })?;
+        Ok(())
+    }
+
+    /// Turn an indicator on or off, Caps Lock and Num Lock change the locks.
+    pub fn set_indicator(
+        &self,
+        connection: &xcb::Connection,
+        name: &str,
+        on: bool,
+    ) -> anyhow::Result<()> {
+        let lock = match name {
+            xkb::LED_NAME_CAPS => Some(Modifiers::CAPS),
+            xkb::LED_NAME_NUM => Some(Modifiers::NUM),
+            _ => None,
+        };
+        if let Some(lock) = lock {
+            let mut locks = self.get_modifier_state().locked;
+            locks.set(lock, on);
+            return self.set_locks(connection, locks);
+        }
+
+        let cookie = connection.send_request(&xcb::x::InternAtom {
+            only_if_exists: false,
+            name: name.as_bytes(),
+        });
+        let indicator = connection.wait_for_reply(cookie)?.atom();
+
+        connection.send_and_check_request(&xcb::xkb::SetNamedIndicator {
+            device_spec: self.device_id.get().into(),
+            led_class: xcb::xkb::LedClass::DfltXiClass,
+            led_id: xcb::xkb::Id::DfltXiId as _,
+            indicator,
+            set_state: true,
+            on,
+            set_map: false,
+            create_map: false,
+            map_flags: xcb::xkb::ImFlag::empty(),
+            map_which_groups: xcb::xkb::ImGroupsWhich::empty(),
+            map_groups: xcb::xkb::SetOfGroups::empty(),
+            map_which_mods: xcb::xkb::ImModsWhich::empty(),
+            map_real_mods: xcb::x::ModMask::empty(),
+            map_vmods: xcb::xkb::VMod::empty(),
+            map_ctrls: xcb::xkb::BoolCtrl::empty(),
+        })?;
+        Ok(())
+    }
+
+    pub fn get_auto_repeat(&self, connection: &xcb::Connection) -> anyhow::Result<AutoRepeat> {
+        let cookie = connection.send_request(&xcb::xkb::GetControls {
+            device_spec: self.device_id.get().into(),
+        });
+        let reply = connection.wait_for_reply(cookie)?;
+
+        Ok(AutoRepeat {
+            enabled: reply
+                .enabled_controls()
+                .contains(xcb::xkb::BoolCtrl::REPEAT_KEYS),
+            delay: reply.repeat_delay(),
+            interval: reply.repeat_interval(),
+            per_key: *reply.per_key_repeat(),
+        })
+    }
+
+    pub fn set_auto_repeat(
+        &self,
+        connection: &xcb::Connection,
+        auto_repeat: &AutoRepeat,
+    ) -> anyhow::Result<()> {
+        if self.original_auto_repeat.get().is_none() {
+            let original = self.get_auto_repeat(connection)?;
+            self.original_auto_repeat.set(Some(original));
+        }
+        self.send_auto_repeat(connection, auto_repeat)
+    }
+
+    pub fn set_repeat_rate(
+        &self,
+        connection: &xcb::Connection,
+        delay: u16,
+        interval: u16,
+    ) -> anyhow::Result<()> {
+        let mut auto_repeat = self.get_auto_repeat(connection)?;
+        auto_repeat.delay = delay;
+        auto_repeat.interval = interval;
+        self.set_auto_repeat(connection, &auto_repeat)
+    }
+
+    pub fn set_auto_repeat_enabled(
+        &self,
+        connection: &xcb::Connection,
+        enabled: bool,
+    ) -> anyhow::Result<()> {
+        let mut auto_repeat = self.get_auto_repeat(connection)?;
+        auto_repeat.enabled = enabled;
+        self.set_auto_repeat(connection, &auto_repeat)
+    }
+
+    pub fn set_key_auto_repeat(
+        &self,
+        connection: &xcb::Connection,
+        keycode: u8,
+        enabled: bool,
+    ) -> anyhow::Result<()> {
+        let mut auto_repeat = self.get_auto_repeat(connection)?;
+        auto_repeat.set_key_enabled(keycode, enabled);
+        self.set_auto_repeat(connection, &auto_repeat)
+    }
+
+    /// Put back the autorepeat found before the first change, if any.
+    pub fn restore_auto_repeat(&self, connection: &xcb::Connection) -> anyhow::Result<()> {
+        match self.original_auto_repeat.take() {
+            Some(original) => self.send_auto_repeat(connection, &original),
+            None => Ok(()),
+        }
+    }
+
+    fn send_auto_repeat(
+        &self,
+        connection: &xcb::Connection,
+        auto_repeat: &AutoRepeat,
+    ) -> anyhow::Result<()> {
+        let repeat_keys = xcb::xkb::BoolCtrl::REPEAT_KEYS;
+        let enabled_controls = match auto_repeat.enabled {
+            true => repeat_keys,
+            false => xcb::xkb::BoolCtrl::empty(),
+        };
+        // The RepeatKeys bit of change_controls selects the delay and the
+        // interval, it is missing from the generated flags.
+        let change_controls = xcb::xkb::Control::PER_KEY_REPEAT
+            | xcb::xkb::Control::CONTROLS_ENABLED
+            | unsafe { xcb::xkb::Control::from_bits_unchecked(repeat_keys.bits()) };
+
+        connection.send_and_check_request(&xcb::xkb::SetControls {
+            affect_enabled_controls: repeat_keys,
+            enabled_controls,
+            change_controls,
+            repeat_delay: auto_repeat.delay,
+            repeat_interval: auto_repeat.interval,
+            per_key_repeat: auto_repeat.per_key,
+            ..self.set_controls_request()
+        })?;
+        Ok(())
+    }
+
+    /// A SetControls changing nothing.
+    fn set_controls_request(&self) -> xcb::xkb::SetControls {
+        xcb::xkb::SetControls {
+            device_spec: self.device_id.get().into(),
+            affect_internal_real_mods: xcb::x::ModMask::empty(),
+            internal_real_mods: xcb::x::ModMask::empty(),
+            affect_ignore_lock_real_mods: xcb::x::ModMask::empty(),
+            ignore_lock_real_mods: xcb::x::ModMask::empty(),
+            affect_internal_virtual_mods: xcb::xkb::VMod::empty(),
+            internal_virtual_mods: xcb::xkb::VMod::empty(),
+            affect_ignore_lock_virtual_mods: xcb::xkb::VMod::empty(),
+            ignore_lock_virtual_mods: xcb::xkb::VMod::empty(),
+            mouse_keys_dflt_btn: 0,
+            groups_wrap: 0,
+            access_x_options: xcb::xkb::AxOption::empty(),
+            affect_enabled_controls: xcb::xkb::BoolCtrl::empty(),
+            enabled_controls: xcb::xkb::BoolCtrl::empty(),
+            change_controls: xcb::xkb::Control::empty(),
+            repeat_delay: 0,
+            repeat_interval: 0,
+            slow_keys_delay: 0,
+            debounce_delay: 0,
+            mouse_keys_delay: 0,
+            mouse_keys_interval: 0,
+            mouse_keys_time_to_max: 0,
+            mouse_keys_max_speed: 0,
+            mouse_keys_curve: 0,
+            access_x_timeout: 0,
+            access_x_timeout_mask: xcb::xkb::BoolCtrl::empty(),
+            access_x_timeout_values: xcb::xkb::BoolCtrl::empty(),
+            access_x_timeout_options_mask: xcb::xkb::AxOption::empty(),
+            access_x_timeout_options_values: xcb::xkb::AxOption::empty(),
+            per_key_repeat: [0; 32],
         }
-        res
+    }
+
+    /// The real modifiers the server keeps for itself: they still select the
+    /// levels of the keys but are not reported to the clients.
+    pub fn get_internal_mods(&self, connection: &xcb::Connection) -> anyhow::Result<xkb::ModMask> {
+        let cookie = connection.send_request(&xcb::xkb::GetControls {
+            device_spec: self.device_id.get().into(),
+        });
+        let reply = connection.wait_for_reply(cookie)?;
+        Ok(reply.internal_mods_real_mods().bits())
+    }
+
+    pub fn set_internal_mods(
+        &self,
+        connection: &xcb::Connection,
+        mask: xkb::ModMask,
+    ) -> anyhow::Result<()> {
+        let internal_real_mods = xcb::x::ModMask::from_bits_truncate(mask);
+        connection.send_and_check_request(&xcb::xkb::SetControls {
+            affect_internal_real_mods: xcb::x::ModMask::all(),
+            internal_real_mods,
+            change_controls: xcb::xkb::Control::INTERNAL_MODS,
+            ..self.set_controls_request()
+        })?;
+        Ok(())
+    }
+
+    /// The modifiers set by holding `keycode`.
+    pub fn get_key_modifiers(&self, keycode: xkb::Keycode) -> Modifiers {
+        let mut state = xkb::State::new(&self.keymap.borrow());
+        state.update_key(keycode, xkb::KeyDirection::Down);
+        self.get_modifier_map()
+            .to_modifiers(state.serialize_mods(xkb::STATE_MODS_DEPRESSED))
+    }
+
+    /// Receive the layout, keymap, modifiers and locks changes seen by
+    /// `process_xkb_event`.
+    pub fn subscribe(&self) -> Receiver<KeyboardEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.borrow_mut().push(sender);
+        receiver
+    }
+
+    fn notify(&self, event: KeyboardEvent) {
+        log::debug!("keyboard event: {:?}", event);
+        // Forget the subscribers which dropped their receiver.
+        self.subscribers
+            .borrow_mut()
+            .retain(|sender| sender.send(event.clone()).is_ok());
     }
 
     pub fn get_device_id(&self) -> u8 {
-        self.device_id
+        self.device_id.get()
+    }
+
+    /// The device was removed or replaced: follow the core keyboard or the
+    /// device taking the place of ours.
+    fn resolve_device(
+        &self,
+        connection: &xcb::Connection,
+        ev: &xcb::xkb::NewKeyboardNotifyEvent,
+    ) -> anyhow::Result<()> {
+        let old_device = self.get_device_id();
+        let new_device = if self.core_device {
+            let device_id = xkb::x11::get_core_keyboard_device_id(connection);
+            anyhow::ensure!(device_id != -1, "Couldn't find core keyboard device");
+            device_id as u8
+        } else if ev.old_device_id() == old_device {
+            ev.device_id()
+        } else {
+            old_device
+        };
+
+        if new_device != old_device {
+            log::info!("Keyboard device {old_device} => {new_device}");
+            select_events(connection, new_device)?;
+            self.device_id.set(new_device);
+            self.update_indicator_state(connection)?;
+            self.notify(KeyboardEvent::DeviceChanged {
+                old_device,
+                new_device,
+            });
+        }
+        Ok(())
     }
 
     pub fn get_keycode_by_keysym(&self, keysym: u32) -> Option<u32> {
@@ -218,18 +805,195 @@ impl XKeyboard {
         }
     }
 
-    pub fn get_key_event_by_keysym(&self, keysym: u32) -> Option<KeyEvent> {
-        let keysym_map = self.keysym_event_map.borrow();
-        if !keysym_map.contains_key(&keysym) {
-            None
-        } else {
-            keysym_map.get(&keysym).cloned()
+    /// Get the preferred way to type `keysym` in the active layout.
+    pub fn get_plan_by_keysym(&self, keysym: u32) -> Option<KeysymPlan> {
+        let layout: u32 = self.group_index.borrow().to_owned().into();
+        self.keysym_plan_map
+            .borrow()
+            .get(&keysym)?
+            .iter()
+            .find(|plan| plan.layout == layout)
+            .copied()
+    }
+
+    /// Get the preferred way to type `keysym`, look in the other layouts
+    /// if the active one doesn't have it.
+    pub fn get_plan_in_any_layout(&self, keysym: u32) -> Option<KeysymPlan> {
+        self.get_plan_by_keysym(keysym)
+            .or_else(|| self.keysym_plan_map.borrow().get(&keysym)?.first().copied())
+    }
+
+    /// Get the preferred way to type `keysym` while `locks` stay as they are:
+    /// the plan holds the modifiers which give the keysym under the locks,
+    /// e.g. SHIFT for 'a' when CAPS is locked.
+    ///
+    /// If no held modifiers work, NUM is toggled for the time of the plan,
+    /// e.g. for KP_1 when NUM is off, see `KeysymPlan::locks`.
+    pub fn get_plan_with_locks(&self, keysym: u32, locks: Modifiers) -> Option<KeysymPlan> {
+        let locks = locks & Modifiers::LOCKS;
+        self.get_plan_under_locks(keysym, locks).or_else(|| {
+            let toggled = locks ^ Modifiers::NUM;
+            let plan = self.get_plan_under_locks(keysym, toggled)?;
+            Some(KeysymPlan {
+                locks: Some(toggled),
+                ..plan
+            })
+        })
+    }
+
+    fn get_plan_under_locks(&self, keysym: u32, locks: Modifiers) -> Option<KeysymPlan> {
+        let keymap = self.keymap.borrow();
+        let modifier_map = self.get_modifier_map();
+        let active_layout: u32 = self.get_active_group_index().into();
+        let locked_mask = modifier_map.to_mod_mask(locks);
+
+        let mut plans = self.keysym_plan_map.borrow().get(&keysym)?.clone();
+        plans.sort_by_key(|plan| plan.layout != active_layout);
+
+        let mut held_candidates: Vec<Modifiers> = (0..1 << LEVEL_MODIFIERS.len())
+            .map(|bits: usize| {
+                LEVEL_MODIFIERS
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| bits & (1 << i) != 0)
+                    .fold(Modifiers::NONE, |res, (_, &modifier)| res | modifier)
+            })
+            // A modifier on no real modifier can't select a level.
+            .filter(|&held| {
+                LEVEL_MODIFIERS.iter().all(|&modifier| {
+                    !held.contains(modifier) || modifier_map.to_mod_mask(modifier) != 0
+                })
+            })
+            .collect();
+        held_candidates.sort_by_key(|held| held.bits().count_ones());
+
+        for plan in plans {
+            let planned = plan.modifiers - Modifiers::LOCKS;
+            let candidates = std::iter::once(planned).chain(held_candidates.iter().copied());
+            for held in candidates {
+                let mut state = xkb::State::new(&keymap);
+                state.update_mask(
+                    modifier_map.to_mod_mask(held),
+                    0,
+                    locked_mask,
+                    0,
+                    0,
+                    plan.layout,
+                );
+                if is_same_keysym(state.key_get_one_sym(plan.keycode), keysym) {
+                    return Some(KeysymPlan {
+                        level: state.key_get_level(plan.keycode, plan.layout),
+                        modifiers: held,
+                        ..plan
+                    });
+                }
+            }
         }
+        None
+    }
+
+    /// Type `chr` with a dead key then a base key of the keymap, see
+    /// `keysyms::decompose_dead_key`.
+    pub fn get_dead_key_plans(&self, chr: char, locks: Modifiers) -> Option<[KeysymPlan; 2]> {
+        let (dead_key, base) = keysyms::decompose_dead_key(chr)?;
+        let dead_plan = self.get_plan_with_locks(dead_key.keysym, locks)?;
+        let base_plan = self.get_plan_with_locks(keysyms::char_to_keysym(base), locks)?;
+        Some([dead_plan, base_plan])
+    }
+
+    /// The keyboards compiled without X server have no Compose table.
+    pub fn set_compose_table(&self, compose_table: Option<ComposeTable>) {
+        self.compose_table.replace(compose_table);
+        self.compose_sequences.borrow_mut().clear();
+    }
+
+    pub fn get_context(&self) -> &xkb::Context {
+        &self.context
+    }
+
+    /// Type `chr` with the shortest Compose sequence of the active layout.
+    pub fn get_compose_plans(&self, chr: char, locks: Modifiers) -> Option<Vec<KeysymPlan>> {
+        let compose_table = self.compose_table.borrow();
+        let compose_table = compose_table.as_ref()?;
+        let layout: u32 = self.get_active_group_index().into();
+
+        let mut compose_sequences = self.compose_sequences.borrow_mut();
+        if self.compose_stale.take() {
+            compose_sequences
+                .retain(|&layout, sequences| sequences.is_valid_for(&self.layout_keysyms(layout)));
+        }
+        let sequences = compose_sequences
+            .entry(layout)
+            .or_insert_with(|| compose_table.shortest_sequences(self.layout_keysyms(layout)));
+
+        sequences
+            .get(chr)?
+            .iter()
+            .map(|&keysym| self.get_plan_with_locks(keysym, locks))
+            .collect()
+    }
+
+    /// The keysyms on the keys of `layout`, sorted, without the other forms
+    /// of their chars.
+    fn layout_keysyms(&self, layout: xkb::LayoutIndex) -> Vec<xkb::Keysym> {
+        let keymap = self.keymap.borrow();
+        let mut keysyms: Vec<xkb::Keysym> = self
+            .keysym_plan_map
+            .borrow()
+            .iter()
+            .filter(|(&keysym, plans)| {
+                plans.iter().any(|plan| {
+                    plan.layout == layout
+                        && keymap.key_get_syms_by_level(plan.keycode, layout, plan.level)
+                            == [keysym]
+                })
+            })
+            .map(|(&keysym, _)| keysym)
+            .collect();
+        keysyms.sort_unstable();
+        keysyms
+    }
+
+    /// Get the keysym the key produces in the active layout with `modifiers`
+    /// held, and the modifiers it doesn't consume. CAPS and NUM are taken as
+    /// locks.
+    pub fn get_keysym_by_modifiers(
+        &self,
+        keycode: xkb::Keycode,
+        modifiers: Modifiers,
+    ) -> (xkb::Keysym, Modifiers) {
+        let locks = Modifiers::LOCKS;
+        let keymap = self.keymap.borrow();
+        let modifier_map = self.get_modifier_map();
+        let layout: u32 = self.get_active_group_index().into();
+
+        let mut state = xkb::State::new(&keymap);
+        state.update_mask(
+            modifier_map.to_mod_mask(modifiers - locks),
+            0,
+            modifier_map.to_mod_mask(modifiers & locks),
+            0,
+            0,
+            layout,
+        );
+
+        let keysym = state.key_get_one_sym(keycode);
+        let consumed = modifier_map.to_modifiers(state.key_get_consumed_mods(keycode));
+
+        (keysym, modifiers - consumed)
+    }
+
+    pub fn get_active_group_index(&self) -> GroupIndex {
+        self.group_index.borrow().to_owned()
+    }
+
+    pub fn get_key_event_by_keysym(&self, keysym: u32) -> Option<KeyEvent> {
+        self.get_plan_by_keysym(keysym)
+            .map(|plan| plan.key_event(false))
     }
 
     pub fn keysym_is_dead_key(&self, keysym: xkb::Keysym) -> bool {
-        let name = xkb::keysym_get_name(keysym);
-        name.starts_with("dead")
+        keysyms::is_dead_keysym(keysym)
     }
 
     pub fn get_active_layout_name(&self) -> String {
@@ -240,6 +1004,107 @@ impl XKeyboard {
         layout_name.to_owned()
     }
 
+    /// Read `_XKB_RULES_NAMES` from the root window.
+    pub fn get_rule_names(
+        &self,
+        connection: &xcb::Connection,
+        root: xcb::x::Window,
+    ) -> anyhow::Result<Option<RuleNames>> {
+        let cookie = connection.send_request(&xcb::x::InternAtom {
+            only_if_exists: true,
+            name: b"_XKB_RULES_NAMES",
+        });
+        let atom = connection.wait_for_reply(cookie)?.atom();
+        if atom == xcb::x::ATOM_NONE {
+            return Ok(None);
+        }
+
+        let cookie = connection.send_request(&xcb::x::GetProperty {
+            delete: false,
+            window: root,
+            property: atom,
+            r#type: xcb::x::ATOM_STRING,
+            long_offset: 0,
+            long_length: 1024,
+        });
+        let reply = connection.wait_for_reply(cookie)?;
+        if reply.value::<u8>().is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(RuleNames::from_property(reply.value())))
+    }
+
+    /// Describe the groups of the keymap, the short names and variants come
+    /// from the rule names.
+    pub fn get_layout_info(
+        &self,
+        connection: &xcb::Connection,
+        root: xcb::x::Window,
+    ) -> anyhow::Result<LayoutInfo> {
+        let rule_names = self.get_rule_names(connection, root)?;
+        let keymap = self.keymap.borrow();
+        let num_groups = keymap.num_layouts();
+
+        let groups = (0..num_groups)
+            .map(|index| {
+                let nth = |names: Option<&Vec<String>>| {
+                    names
+                        .and_then(|names| names.get(index as usize))
+                        .cloned()
+                        .unwrap_or_default()
+                };
+                LayoutGroup {
+                    index,
+                    name: keymap.layout_get_name(index).to_owned(),
+                    short_name: nth(rule_names.as_ref().map(|names| &names.layouts)),
+                    variant: nth(rule_names.as_ref().map(|names| &names.variants)),
+                }
+            })
+            .collect();
+
+        Ok(LayoutInfo {
+            rule_names,
+            groups,
+            active_group: self.get_active_group_index().into(),
+            num_groups,
+        })
+    }
+
+    /// Serialize the keymap in the xkbcommon text format, it can be compiled
+    /// again by `xkb::Keymap::new_from_string`.
+    pub fn get_keymap_string(&self) -> String {
+        self.keymap
+            .borrow()
+            .get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1)
+    }
+
+    pub fn describe_keymap(
+        &self,
+        connection: &xcb::Connection,
+        root: xcb::x::Window,
+    ) -> anyhow::Result<KeymapDescription> {
+        let layout_info = self.get_layout_info(connection, root)?;
+        let keymap = self.keymap.borrow();
+        let modifier_map = self.get_modifier_map();
+
+        let mut keys: Vec<KeyDescription> = self
+            .phys_code_map
+            .borrow()
+            .iter()
+            .map(|(&phys, &keycode)| KeyDescription {
+                phys,
+                keycode,
+                groups: (0..keymap.num_layouts_for_key(keycode))
+                    .map(|layout| describe_levels(&keymap, &modifier_map, keycode, layout))
+                    .collect(),
+            })
+            .collect();
+        keys.sort_by_key(|key| key.keycode);
+
+        Ok(KeymapDescription { layout_info, keys })
+    }
+
     pub fn update_state(&self, ev: &xcb::xkb::StateNotifyEvent) {
         self.state.borrow_mut().update_mask(
             ev.base_mods().bits(),
@@ -249,6 +1114,10 @@ impl XKeyboard {
             ev.latched_group() as xkb::LayoutIndex,
             ev.locked_group() as u32,
         );
+        self.modifier_state.set(modifier_state_from(
+            &self.state.borrow(),
+            &self.get_modifier_map(),
+        ));
     }
 
     pub fn update_keymap(
@@ -266,32 +1135,49 @@ impl XKeyboard {
         for keycode in min_keycode..max_keycode {
             let keysym = current_state.key_get_one_sym(keycode);
             if keysym == 0 {
-                new_unused_keycodes.push(keysym);
+                new_unused_keycodes.push(keycode);
             } else {
                 new_keysym_keycode_map.insert(keysym, keycode);
             }
         }
 
         let new_group_index = get_active_group_index(current_state, current_keymap);
-        let new_keysym_event_map: HashMap<u32, KeyEvent> = build_keysym_event_map(
-            current_keymap,
-            min_keycode,
-            max_keycode,
-            new_group_index.into(),
-        );
+        let new_keysym_plan_map = build_keysym_plan_map(current_keymap, &self.get_modifier_map());
 
         self.phys_code_map.replace(phys_code_map);
         self.code_phys_map.replace(code_phys_map);
-        self.keysym_event_map.replace(new_keysym_event_map);
+        self.keysym_plan_map.replace(new_keysym_plan_map);
+        self.modifier_keycodes
+            .replace(build_modifier_keycodes(current_keymap));
         self.keysym_keycode_map.replace(new_keysym_keycode_map);
+        self.compose_stale.set(true);
         self.unused_keycodes.replace(new_unused_keycodes);
 
-        // todo & warning: why group_index didn't change before and after replace?
         self.group_index.replace(new_group_index);
 
         Ok(())
     }
 
+    /// Switching group only changes which plans are active, the keymap stays
+    /// the same so there is no need to rebuild everything.
+    pub fn update_group(&self, new_group_index: GroupIndex) {
+        let layout: u32 = new_group_index.into();
+        let new_keysym_keycode_map = self
+            .keysym_plan_map
+            .borrow()
+            .iter()
+            .filter_map(|(keysym, plans)| {
+                plans
+                    .iter()
+                    .find(|plan| plan.layout == layout && plan.level == 0)
+                    .map(|plan| (*keysym, plan.keycode))
+            })
+            .collect();
+
+        self.keysym_keycode_map.replace(new_keysym_keycode_map);
+        self.group_index.replace(new_group_index);
+    }
+
     pub fn update_keymaps(&self, connection: &xcb::Connection) -> anyhow::Result<()> {
         let new_keymap = xkb::x11::keymap_new_from_device(
             &self.context,
@@ -306,8 +1192,12 @@ impl XKeyboard {
         let new_state =
             xkb::x11::state_new_from_device(&new_keymap, connection, self.get_device_id().into());
         anyhow::ensure!(!new_state.get_raw_ptr().is_null(), "problem with new state");
+        let new_modifier_map = ModifierMap::query(connection, self.get_device_id(), &new_keymap)?;
+        self.modifier_map.set(new_modifier_map);
 
         self.update_keymap(&new_keymap, &new_state)?;
+        self.modifier_state
+            .set(modifier_state_from(&new_state, &new_modifier_map));
 
         self.state.replace(new_state);
         self.keymap.replace(new_keymap);
@@ -326,27 +1216,156 @@ impl XKeyboard {
             xcb::Event::Xkb(xcb::xkb::Event::StateNotify(ev)) => {
                 let new_group_index = GroupIndex::from(ev.group());
                 let cur_group_index = self.group_index.borrow().to_owned();
+                let locks = Modifiers::LOCKS;
+                let cur_state = self.get_modifier_state();
 
+                self.update_state(ev);
                 if new_group_index != cur_group_index {
-                    self.update_keymap(&self.keymap.borrow(), &self.state.borrow())?;
+                    self.update_group(new_group_index);
+                    self.notify(KeyboardEvent::LayoutGroupChanged {
+                        old_group: cur_group_index.into(),
+                        new_group: new_group_index.into(),
+                        name: self.get_active_layout_name(),
+                    });
                 }
 
-                self.update_state(ev);
+                let new_state = self.get_modifier_state();
+                let (cur_modifiers, new_modifiers) =
+                    (cur_state.effective() - locks, new_state.effective() - locks);
+                if new_modifiers != cur_modifiers {
+                    self.notify(KeyboardEvent::ModifiersChanged {
+                        old: cur_modifiers,
+                        new: new_modifiers,
+                    });
+                }
+                let (cur_locks, new_locks) = (cur_state.locked & locks, new_state.locked & locks);
+                if new_locks != cur_locks {
+                    self.notify(KeyboardEvent::LockStateChanged {
+                        old: cur_locks,
+                        new: new_locks,
+                    });
+                }
             }
-            xcb::Event::Xkb(
-                xcb::xkb::Event::MapNotify(_) | xcb::xkb::Event::NewKeyboardNotify(_),
-            ) => {
+            xcb::Event::Xkb(xcb::xkb::Event::IndicatorStateNotify(ev)) => {
+                self.indicator_state.set(ev.state());
+
+                let keymap = self.keymap.borrow();
+                for index in 0..keymap.num_leds() {
+                    if ev.state_changed() & (1 << index) == 0 {
+                        continue;
+                    }
+                    self.notify(KeyboardEvent::IndicatorChanged {
+                        name: keymap.led_get_name(index).to_owned(),
+                        active: ev.state() & (1 << index) != 0,
+                    });
+                }
+            }
+            xcb::Event::Xkb(xcb::xkb::Event::NewKeyboardNotify(ev)) => {
+                if ev.changed().contains(xcb::xkb::NknDetail::DEVICE_ID) {
+                    self.resolve_device(connection, ev)?;
+                }
+                // Another keymap, not a remap.
+                self.compose_sequences.borrow_mut().clear();
+                self.update_keymaps(connection)?;
+                self.notify(KeyboardEvent::KeymapReloaded);
+            }
+            xcb::Event::Xkb(xcb::xkb::Event::MapNotify(_)) => {
                 self.update_keymaps(connection)?;
+                self.notify(KeyboardEvent::KeymapReloaded);
             }
             _ => {}
         }
         Ok(())
     }
+}
 
-    fn mod_is_active(&self, modifier: &str) -> bool {
-        self.state
-            .borrow()
-            .mod_name_is_active(modifier, xkb::STATE_MODS_EFFECTIVE)
+/// Set the keyboard events that need to be monitored.
+fn select_events(connection: &xcb::Connection, device_id: u8) -> anyhow::Result<()> {
+    let map_parts = xcb::xkb::MapPart::KEY_TYPES
+        | xcb::xkb::MapPart::KEY_SYMS
+        | xcb::xkb::MapPart::MODIFIER_MAP
+        | xcb::xkb::MapPart::EXPLICIT_COMPONENTS
+        | xcb::xkb::MapPart::KEY_ACTIONS
+        | xcb::xkb::MapPart::KEY_BEHAVIORS
+        | xcb::xkb::MapPart::VIRTUAL_MODS
+        | xcb::xkb::MapPart::VIRTUAL_MOD_MAP;
+
+    let events = xcb::xkb::EventType::NEW_KEYBOARD_NOTIFY
+        | xcb::xkb::EventType::MAP_NOTIFY
+        | xcb::xkb::EventType::STATE_NOTIFY
+        | xcb::xkb::EventType::INDICATOR_STATE_NOTIFY;
+    connection.check_request(connection.send_request_checked(&xcb::xkb::SelectEvents {
+        device_spec: device_id.into(),
+        affect_which: events,
+        clear: xcb::xkb::EventType::empty(),
+        select_all: events,
+        affect_map: map_parts,
+        map: map_parts,
+        details: &[],
+    }))?;
+    Ok(())
+}
+
+/// The master and slave keyboards, XInput 2 is announced first.
+pub fn list_keyboard_devices(connection: &xcb::Connection) -> anyhow::Result<Vec<KeyboardDevice>> {
+    use xcb::xinput::{DeviceClassType, DeviceType};
+
+    let cookie = connection.send_request(&xcb::xinput::XiQueryVersion {
+        major_version: 2,
+        minor_version: 0,
+    });
+    let version = connection.wait_for_reply(cookie)?;
+    anyhow::ensure!(
+        version.major_version() >= 2,
+        "XInput2 not supported, the server has XInput {}.{}",
+        version.major_version(),
+        version.minor_version()
+    );
+
+    let cookie = connection.send_request(&xcb::xinput::XiQueryDevice {
+        device: xcb::xinput::Device::All,
+    });
+    let reply = connection.wait_for_reply(cookie)?;
+
+    let devices = reply
+        .infos()
+        .filter_map(|info| {
+            let kind = match info.r#type() {
+                DeviceType::MasterKeyboard => KeyboardDeviceKind::Master,
+                DeviceType::SlaveKeyboard => KeyboardDeviceKind::Slave,
+                // Floating pointers have no keys.
+                DeviceType::FloatingSlave
+                    if info
+                        .classes()
+                        .any(|class| class.r#type() == DeviceClassType::Key) =>
+                {
+                    KeyboardDeviceKind::Floating
+                }
+                _ => return None,
+            };
+            let name = info.name().to_utf8().into_owned();
+            Some(KeyboardDevice {
+                id: info.device().id(),
+                is_xtest: name.contains("XTEST"),
+                name,
+                kind,
+                attachment: info.attachment().id(),
+                enabled: info.enabled(),
+            })
+        })
+        .collect();
+    Ok(devices)
+}
+
+pub fn modifier_state_from(state: &xkb::State, modifier_map: &ModifierMap) -> ModifierState {
+    let get_modifiers =
+        |component: xkb::StateComponent| modifier_map.to_modifiers(state.serialize_mods(component));
+
+    ModifierState {
+        base: get_modifiers(xkb::STATE_MODS_DEPRESSED),
+        latched: get_modifiers(xkb::STATE_MODS_LATCHED),
+        locked: get_modifiers(xkb::STATE_MODS_LOCKED),
+        group: state.serialize_layout(xkb::STATE_LAYOUT_EFFECTIVE),
     }
 }
 
@@ -395,3 +1414,14 @@ impl From<xcb::xkb::Group> for GroupIndex {
         }
     }
 }
+
+impl From<GroupIndex> for xcb::xkb::Group {
+    fn from(group_index: GroupIndex) -> Self {
+        match group_index {
+            GroupIndex::N1 => Self::N1,
+            GroupIndex::N2 => Self::N2,
+            GroupIndex::N3 => Self::N3,
+            GroupIndex::N4 => Self::N4,
+        }
+    }
+}
@@ -1,8 +1,15 @@
+#[cfg(feature = "tokio")]
+pub mod async_simulator;
+pub mod compose;
 pub mod connection;
 pub mod keyboard;
 pub mod keycodes;
+pub mod modmap;
+pub mod remap;
 pub mod simulator;
+pub mod translator;
 
 pub use connection::XConnection as Connection;
 pub use keyboard::XKeyboard as Keyboard;
 pub use simulator::XSimulator as Simulator;
+pub use translator::LayoutTranslator;
@@ -0,0 +1,262 @@
+use crate::types::{Modifiers, PhysKeyCode};
+
+use super::keycodes::build_phys_keycode_map;
+
+use xkbcommon::xkb::{self, keysyms};
+
+/// The real modifiers of the X server, in the order of their bits.
+pub const REAL_MOD_NAMES: [&str; 8] = [
+    xkb::MOD_NAME_SHIFT,
+    xkb::MOD_NAME_CAPS,
+    xkb::MOD_NAME_CTRL,
+    "Mod1",
+    "Mod2",
+    "Mod3",
+    "Mod4",
+    "Mod5",
+];
+
+/// When a real modifier has several meanings, the first one is used to
+/// translate it into `Modifiers`.
+const MODIFIER_PRIORITY: [Modifiers; 11] = [
+    Modifiers::SHIFT,
+    Modifiers::CAPS,
+    Modifiers::CTRL,
+    Modifiers::NUM,
+    Modifiers::SCROLL,
+    Modifiers::ALT,
+    Modifiers::ALT_GR,
+    Modifiers::LEVEL5,
+    Modifiers::META,
+    Modifiers::SUPER,
+    Modifiers::HYPER,
+];
+
+/// The meaning of the keysyms found in the modifier mapping. META is the
+/// logo key whatever its keysym, see `logo_keycodes`.
+fn keysym_modifier(keysym: xkb::Keysym) -> Modifiers {
+    match keysym {
+        keysyms::KEY_Shift_L | keysyms::KEY_Shift_R => Modifiers::SHIFT,
+        keysyms::KEY_Caps_Lock | keysyms::KEY_Shift_Lock => Modifiers::CAPS,
+        keysyms::KEY_Control_L | keysyms::KEY_Control_R => Modifiers::CTRL,
+        keysyms::KEY_Num_Lock => Modifiers::NUM,
+        keysyms::KEY_Scroll_Lock => Modifiers::SCROLL,
+        keysyms::KEY_Alt_L | keysyms::KEY_Alt_R => Modifiers::ALT,
+        keysyms::KEY_ISO_Level3_Shift | keysyms::KEY_Mode_switch => Modifiers::ALT_GR,
+        keysyms::KEY_ISO_Level5_Shift => Modifiers::LEVEL5,
+        keysyms::KEY_Meta_L | keysyms::KEY_Meta_R => Modifiers::META,
+        keysyms::KEY_Super_L | keysyms::KEY_Super_R => Modifiers::SUPER,
+        keysyms::KEY_Hyper_L | keysyms::KEY_Hyper_R => Modifiers::HYPER,
+        _ => Modifiers::NONE,
+    }
+}
+
+/// META stands for the logo keys, like on the Windows side.
+fn logo_keycodes(keymap: &xkb::Keymap) -> Vec<xkb::Keycode> {
+    let (_, phys_code_map) = build_phys_keycode_map(keymap);
+    [PhysKeyCode::MetaLeft, PhysKeyCode::MetaRight]
+        .iter()
+        .filter_map(|phys| phys_code_map.get(phys).copied())
+        .collect()
+}
+
+/// The meaning of the XKB virtual modifiers.
+fn vmod_modifier(name: &str) -> Modifiers {
+    match name {
+        "NumLock" => Modifiers::NUM,
+        "ScrollLock" => Modifiers::SCROLL,
+        "Alt" => Modifiers::ALT,
+        "AltGr" | "LevelThree" => Modifiers::ALT_GR,
+        "LevelFive" => Modifiers::LEVEL5,
+        "Meta" => Modifiers::META,
+        "Super" => Modifiers::SUPER,
+        "Hyper" => Modifiers::HYPER,
+        _ => Modifiers::NONE,
+    }
+}
+
+/// What each real modifier stands for, read from the server instead of
+/// assuming the evdev mapping (Mod1 = Alt, Mod4 = Super, Mod5 = AltGr).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModifierMap {
+    /// All the meanings of each real modifier, from Shift to Mod5.
+    pub meanings: [Modifiers; 8],
+}
+
+impl Default for ModifierMap {
+    /// The evdev mapping.
+    fn default() -> Self {
+        Self {
+            meanings: [
+                Modifiers::SHIFT,
+                Modifiers::CAPS,
+                Modifiers::CTRL,
+                Modifiers::ALT,
+                Modifiers::NUM,
+                Modifiers::NONE,
+                Modifiers::META | Modifiers::SUPER | Modifiers::HYPER,
+                Modifiers::ALT_GR,
+            ],
+        }
+    }
+}
+
+impl ModifierMap {
+    /// Shift, Lock and Control can't be moved.
+    fn fixed() -> Self {
+        let mut meanings = [Modifiers::NONE; 8];
+        meanings[0] = Modifiers::SHIFT;
+        meanings[1] = Modifiers::CAPS;
+        meanings[2] = Modifiers::CTRL;
+        Self { meanings }
+    }
+
+    /// Read the core modifier mapping and the XKB virtual modifiers of the
+    /// device, `keymap` gives the keysyms of the keycodes.
+    pub fn query(
+        connection: &xcb::Connection,
+        device_id: u8,
+        keymap: &xkb::Keymap,
+    ) -> anyhow::Result<Self> {
+        let mut map = Self::fixed();
+        let logo_keycodes = logo_keycodes(keymap);
+
+        let cookie = connection.send_request(&xcb::x::GetModifierMapping {});
+        let reply = connection.wait_for_reply(cookie)?;
+        let keycodes_per_modifier = (reply.keycodes().len() / 8).max(1);
+        for (real, keycodes) in reply.keycodes().chunks(keycodes_per_modifier).enumerate() {
+            for &keycode in keycodes.iter().filter(|&&keycode| keycode != 0) {
+                if logo_keycodes.contains(&keycode.into()) {
+                    map.meanings[real] |= Modifiers::META;
+                }
+                for &keysym in keymap.key_get_syms_by_level(keycode.into(), 0, 0) {
+                    map.meanings[real] |= keysym_modifier(keysym);
+                }
+            }
+        }
+
+        let cookie = connection.send_request(&xcb::xkb::GetMap {
+            device_spec: device_id.into(),
+            full: xcb::xkb::MapPart::VIRTUAL_MODS,
+            partial: xcb::xkb::MapPart::empty(),
+            first_type: 0,
+            n_types: 0,
+            first_key_sym: 0,
+            n_key_syms: 0,
+            first_key_action: 0,
+            n_key_actions: 0,
+            first_key_behavior: 0,
+            n_key_behaviors: 0,
+            virtual_mods: xcb::xkb::VMod::all(),
+            first_key_explicit: 0,
+            n_key_explicit: 0,
+            first_mod_map_key: 0,
+            n_mod_map_keys: 0,
+            first_v_mod_map_key: 0,
+            n_v_mod_map_keys: 0,
+        });
+        let reply = connection.wait_for_reply(cookie)?;
+        let masks = reply
+            .map()
+            .into_iter()
+            .find_map(|part| match part {
+                xcb::xkb::GetMapReplyMap::VirtualMods(masks) => Some(masks),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let vmods = (0..16).filter(|vmod| reply.virtual_mods().bits() & (1 << vmod) != 0);
+        for (vmod, mask) in vmods.zip(masks) {
+            // xkbcommon puts the virtual modifiers of X keymaps after the real ones.
+            let modifier = vmod_modifier(keymap.mod_get_name(REAL_MOD_NAMES.len() as u32 + vmod));
+            map.add_real_mods(mask.bits(), modifier);
+        }
+
+        Ok(map)
+    }
+
+    /// Without X server: press every modifier key of the keymap and look at
+    /// the real modifiers it activates.
+    pub fn from_keymap(keymap: &xkb::Keymap) -> Self {
+        let mut map = Self::fixed();
+        let logo_keycodes = logo_keycodes(keymap);
+
+        for keycode in keymap.min_keycode()..=keymap.max_keycode() {
+            for &keysym in keymap.key_get_syms_by_level(keycode, 0, 0) {
+                let mut modifier = keysym_modifier(keysym);
+                if logo_keycodes.contains(&keycode) {
+                    modifier |= Modifiers::META;
+                }
+                if modifier.is_empty() {
+                    continue;
+                }
+                let mut state = xkb::State::new(keymap);
+                state.update_key(keycode, xkb::KeyDirection::Down);
+                map.add_real_mods(state.serialize_mods(xkb::STATE_MODS_EFFECTIVE), modifier);
+            }
+        }
+
+        map
+    }
+
+    fn add_real_mods(&mut self, mask: xkb::ModMask, modifier: Modifiers) {
+        for (real, meaning) in self.meanings.iter_mut().enumerate() {
+            if mask & (1 << real) != 0 {
+                *meaning |= modifier;
+            }
+        }
+    }
+
+    /// The meaning used to translate the real modifier.
+    pub fn main_meaning(&self, real: usize) -> Modifiers {
+        MODIFIER_PRIORITY
+            .into_iter()
+            .find(|&modifier| self.meanings[real].contains(modifier))
+            .unwrap_or_default()
+    }
+
+    /// Translate a mask of real modifiers, return None if one of them can't be
+    /// expressed by `Modifiers`. Virtual modifiers are already mapped onto
+    /// real ones, their bits are ignored.
+    pub fn try_to_modifiers(&self, mask: xkb::ModMask) -> Option<Modifiers> {
+        let mut res = Modifiers::NONE;
+        for real in 0..self.meanings.len() {
+            if mask & (1 << real) == 0 {
+                continue;
+            }
+            let modifier = self.main_meaning(real);
+            if modifier.is_empty() {
+                return None;
+            }
+            res |= modifier;
+        }
+        Some(res)
+    }
+
+    /// Translate a mask of real modifiers, drop the ones without meaning.
+    pub fn to_modifiers(&self, mask: xkb::ModMask) -> Modifiers {
+        (0..self.meanings.len())
+            .filter(|real| mask & (1 << real) != 0)
+            .fold(Modifiers::NONE, |res, real| res | self.main_meaning(real))
+    }
+
+    /// Translate `Modifiers` into a mask of real modifiers, the positional bits
+    /// must be translated before. A modifier is put on the real modifier it is
+    /// the main meaning of, or else on the first one having it.
+    pub fn to_mod_mask(&self, modifiers: Modifiers) -> xkb::ModMask {
+        let mut mask = 0;
+        for modifier in MODIFIER_PRIORITY {
+            if !modifiers.contains(modifier) {
+                continue;
+            }
+            let real = (0..self.meanings.len())
+                .find(|&real| self.main_meaning(real) == modifier)
+                .or_else(|| {
+                    (0..self.meanings.len()).find(|&real| self.meanings[real].contains(modifier))
+                });
+            if let Some(real) = real {
+                mask |= 1 << real;
+            }
+        }
+        mask
+    }
+}
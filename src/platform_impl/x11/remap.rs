@@ -0,0 +1,193 @@
+use std::ops::RangeInclusive;
+
+/// A keycode lent to a keysym missing from the keymap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Remap {
+    keycode: u8,
+    keysym: u32,
+    /// The mapping of the keycode before, restored at the end.
+    keysyms_per_keycode: u8,
+    original: Vec<u32>,
+}
+
+/// The spare keycodes used to type the keysyms missing from the keymap. When
+/// they are all used, the least recently used one is remapped.
+#[derive(Debug, Clone, Default)]
+pub struct RemapPool {
+    /// The keycodes the pool may take even if they are mapped, None takes the
+    /// keycodes without keysym.
+    pub reserved: Option<RangeInclusive<u8>>,
+    /// The most recently used last.
+    remaps: Vec<Remap>,
+}
+
+impl RemapPool {
+    pub fn with_reserved(reserved: RangeInclusive<u8>) -> Self {
+        Self {
+            reserved: Some(reserved),
+            remaps: vec![],
+        }
+    }
+
+    /// The keycode lent to `keysym`, it becomes the most recently used.
+    pub fn get(&mut self, keysym: u32) -> Option<u8> {
+        let idx = self
+            .remaps
+            .iter()
+            .position(|remap| remap.keysym == keysym)?;
+        let remap = self.remaps.remove(idx);
+        let keycode = remap.keycode;
+        self.remaps.push(remap);
+        Some(keycode)
+    }
+
+    pub fn is_remapped(&self, keycode: u8) -> bool {
+        self.remaps.iter().any(|remap| remap.keycode == keycode)
+    }
+
+    pub fn len(&self) -> usize {
+        self.remaps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaps.is_empty()
+    }
+
+    pub fn contains(&self, keysym: u32) -> bool {
+        self.remaps.iter().any(|remap| remap.keysym == keysym)
+    }
+
+    /// Choose the keycode for a new keysym among `unused_keycodes`, the
+    /// keycodes without keysym, and not `taken`. A keycode of the pool is
+    /// only reused if there is no other one.
+    fn next_keycode(&self, unused_keycodes: &[u32], taken: &[u8]) -> Option<u8> {
+        let is_free = |keycode: u8| !self.is_remapped(keycode) && !taken.contains(&keycode);
+        let free = match &self.reserved {
+            Some(reserved) => reserved.clone().find(|&keycode| is_free(keycode)),
+            None => unused_keycodes
+                .iter()
+                .filter_map(|&keycode| u8::try_from(keycode).ok())
+                // The keymap may not be reloaded yet after a remap.
+                .find(|&keycode| keycode >= 8 && is_free(keycode)),
+        };
+        free.or_else(|| {
+            self.remaps
+                .iter()
+                .map(|remap| remap.keycode)
+                .find(|keycode| !taken.contains(keycode))
+        })
+    }
+
+    /// Lend a keycode to `keysym` and map it on the server.
+    pub fn remap(
+        &mut self,
+        connection: &xcb::Connection,
+        keysym: u32,
+        unused_keycodes: &[u32],
+    ) -> anyhow::Result<Option<u8>> {
+        match self.remap_batch(connection, &[keysym], unused_keycodes)? {
+            0 => Ok(None),
+            _ => Ok(self.get(keysym)),
+        }
+    }
+
+    /// Lend keycodes to the `keysyms` missing from the pool, with one
+    /// ChangeKeyboardMapping per run of consecutive keycodes, stop when the
+    /// pool is full. Return the number of keysyms mapped, the first ones.
+    pub fn remap_batch(
+        &mut self,
+        connection: &xcb::Connection,
+        keysyms: &[u32],
+        unused_keycodes: &[u32],
+    ) -> anyhow::Result<usize> {
+        let mut assigned: Vec<(u8, u32)> = vec![];
+        for &keysym in keysyms {
+            let taken: Vec<u8> = assigned.iter().map(|&(keycode, _)| keycode).collect();
+            match self.next_keycode(unused_keycodes, &taken) {
+                Some(keycode) => assigned.push((keycode, keysym)),
+                None => break,
+            }
+        }
+
+        let mut sorted = assigned.clone();
+        sorted.sort_unstable();
+        // A core mapping change rebuilds the XKB types of the keys, the keys
+        // of the user in between must not be rewritten.
+        for run in sorted.chunk_by(|&(prev, _), &(next, _)| prev + 1 == next) {
+            self.remap_run(connection, run)?;
+        }
+        Ok(assigned.len())
+    }
+
+    /// Map consecutive keycodes.
+    fn remap_run(&mut self, connection: &xcb::Connection, run: &[(u8, u32)]) -> anyhow::Result<()> {
+        let first = run[0].0;
+        let count = run.len() as u8;
+
+        let cookie = connection.send_request(&xcb::x::GetKeyboardMapping {
+            first_keycode: first,
+            count,
+        });
+        let reply = connection.wait_for_reply(cookie)?;
+        let keysyms_per_keycode = reply.keysyms_per_keycode().max(1);
+        let per = keysyms_per_keycode as usize;
+        let mut mapping = reply.keysyms().to_vec();
+        mapping.resize(count as usize * per, 0);
+
+        for (slot, &(keycode, keysym)) in mapping.chunks_mut(per).zip(run) {
+            let remap = match self
+                .remaps
+                .iter()
+                .position(|remap| remap.keycode == keycode)
+            {
+                Some(idx) => {
+                    let remap = self.remaps.remove(idx);
+                    log::debug!("Reuse keycode={keycode} of keysym={:?}", remap.keysym);
+                    Remap { keysym, ..remap }
+                }
+                None => Remap {
+                    keycode,
+                    keysym,
+                    keysyms_per_keycode,
+                    original: slot.to_vec(),
+                },
+            };
+            slot.fill(0);
+            slot[0] = keysym;
+            self.remaps.push(remap);
+        }
+
+        let res = connection.send_and_check_request(&xcb::x::ChangeKeyboardMapping {
+            keycode_count: count,
+            first_keycode: first,
+            keysyms_per_keycode,
+            keysyms: &mapping,
+        });
+        if let Err(err) = res {
+            // Keep the original mappings even if the keycodes are not remapped.
+            for remap in self.remaps.iter_mut() {
+                if run.iter().any(|&(keycode, _)| keycode == remap.keycode) {
+                    remap.keysym = 0;
+                }
+            }
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Give back all the keycodes with their original mapping.
+    pub fn restore(&mut self, connection: &xcb::Connection) -> anyhow::Result<()> {
+        // The failed ones stay in the pool.
+        while let Some(remap) = self.remaps.last() {
+            log::debug!("Restore keycode={}", remap.keycode);
+            connection.send_and_check_request(&xcb::x::ChangeKeyboardMapping {
+                keycode_count: 1,
+                first_keycode: remap.keycode,
+                keysyms_per_keycode: remap.keysyms_per_keycode,
+                keysyms: &remap.original,
+            })?;
+            self.remaps.pop();
+        }
+        Ok(())
+    }
+}
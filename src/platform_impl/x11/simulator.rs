@@ -1,20 +1,18 @@
 use super::connection::XConnection;
-use super::keyboard::MOD_NAME_ISO_LEVEL3_SHIFT;
+use super::keyboard::KeysymPlan;
+use super::remap::RemapPool;
 
 use crate::connection::ConnectionOps;
+use crate::error::{KeyboarderError, Result};
 use crate::keysyms::{self, char_to_keysym};
-use crate::simulate::{Simulate, SENDER};
-use crate::types::{KeyCode, KeyEvent, Modifiers, ServerMode, SimEvent};
+use crate::simulate::{Simulate, SimulatorHandle};
+use crate::types::{
+    GroupIndex, KeyCode, KeyEvent, ModifierDiff, ModifierState, Modifiers, ServerMode, TypeOptions,
+};
 
 use crate::types::PhysKeyCode;
-use anyhow::Context;
-use filedescriptor::Pipe;
-use xkbcommon::xkb;
 
 use std::borrow::Borrow;
-use std::collections::HashMap;
-use std::io::Write;
-use std::thread::JoinHandle;
 use std::{
     collections::HashSet,
     rc::{Rc, Weak},
@@ -25,126 +23,133 @@ const XCB_KEY_RELEASE: u8 = 3;
 
 pub struct XSimulator {
     conn: Weak<XConnection>,
-    device_id: u8,
+    /// None follows the device of the keyboard.
+    device_id: Option<u8>,
     pressed_key: HashSet<u8>,
     root: xcb::x::Window,
     pub mode: Option<ServerMode>,
-    pub rebinding_keysyms: HashMap<u32, u32>,
+    /// The keycodes lent to the keysyms missing from the keymap.
+    pub remap_pool: RemapPool,
+    /// Let the modifiers of the events change CAPS, NUM and SCROLL, by
+    /// default the locks of the user are left alone.
+    pub sync_locks: bool,
+    /// Hide the modifiers held on the physical keyboards while typing, so
+    /// they don't change the chars or fire shortcuts. Only inside `isolated`.
+    pub isolate_physical_mods: bool,
+    /// The internal modifiers of the server before hiding, see `isolated`.
+    original_internal_mods: Option<u32>,
+    hidden_mods: Modifiers,
+    isolation_depth: usize,
 }
 
 impl Simulate for XSimulator {
-    fn spawn_server(mode: ServerMode) -> anyhow::Result<JoinHandle<()>> {
-        let pipe = Pipe::new()?;
-
-        let mut write_fd = pipe.write;
-        let mut read_fd = pipe.read;
-
-        write_fd.set_non_blocking(true)?;
-        read_fd.set_non_blocking(true)?;
-
-        SENDER.lock().unwrap().replace(write_fd);
-
-        Ok({
-            std::thread::spawn(move || match XConnection::with_simulator(mode) {
-                Ok(conn) => {
-                    if let Err(err) = conn.run_message_loop(&mut read_fd) {
-                        log::error!("Failed to process message: {:?}", err);
-                    };
-                }
-                Err(err) => {
-                    log::error!(
-                        "Failed to init Connection, Please check env Display: {:?}",
-                        err
-                    )
-                }
-            })
-        })
-    }
-
-    fn event_to_server(event: &SimEvent) -> anyhow::Result<()> {
-        let mut binding = SENDER.lock().unwrap();
-        let sender = binding.as_mut();
-
-        if let Some(sender) = sender {
-            let buf: Vec<u8> = event.clone().try_into()?;
-            let size = sender.write(&buf)?;
-            if size != buf.len() {
-                log::error!("Can't write key event");
+    fn spawn_server(mode: ServerMode) -> Result<SimulatorHandle> {
+        SimulatorHandle::spawn(move |mut read_fd| match XConnection::with_simulator(mode) {
+            Ok(conn) => {
+                if let Err(err) = conn.run_message_loop(&mut read_fd) {
+                    log::error!("Failed to process message: {:?}", err);
+                };
             }
-            std::thread::sleep(std::time::Duration::from_millis(10));
-        }
-
-        Ok(())
+            Err(err) => {
+                log::error!(
+                    "Failed to init Connection, Please check env Display: {:?}",
+                    err
+                )
+            }
+        })
     }
 
-    fn simulate_keysym(&mut self, keysym: u32, press: bool) {
+    fn simulate_keysym(&mut self, keysym: u32, press: bool) -> Result<()> {
         let keyboard = &self.conn().keyboard;
-        if let Some(keycode) = keyboard.get_keycode_by_keysym(keysym) {
-            log::debug!("simulate keysym {:?} -> {:?}", keysym, press);
-            self.simulate_keycode(keycode, press);
-        } else {
-            log::error!(
-                "No keysym {:?} in {:?}",
+        let keycode = keyboard.get_keycode_by_keysym(keysym).ok_or_else(|| {
+            KeyboarderError::NoKeycodeForKeysym {
                 keysym,
-                keyboard.get_active_layout_name()
-            );
-        };
+                layout: keyboard.get_active_layout_name(),
+            }
+        })?;
+        log::debug!("simulate keysym {:?} -> {:?}", keysym, press);
+        self.simulate_keycode(keycode, press)
     }
 
-    fn simulate_char_without_modifiers(&mut self, chr: char) {
+    fn simulate_char_without_modifiers(&mut self, chr: char) -> Result<()> {
         log::debug!("simulate char: {:?} ", chr);
-        if let Err(err) = self.process_char_impl(chr) {
-            log::error!("Failed to simulate {err:#}")
-        };
+        self.isolated(|simulator| simulator.process_char_impl(chr))
     }
 
-    fn simulate_phys(&mut self, phys: PhysKeyCode, press: bool) {
+    fn simulate_phys(&mut self, phys: PhysKeyCode, press: bool) -> Result<()> {
         let keyboard = &self.conn().keyboard;
-        if let Some(keycode) = keyboard.get_keycode_by_phys(phys) {
-            log::debug!("simulate phys {:?} => {:?}", phys, press);
-            self.simulate_keycode(keycode, press);
-        } else {
-            log::error!(
-                "No PhysKeyCode {:?} in {:?}",
-                phys,
-                keyboard.get_active_layout_name()
-            );
-        };
+        let keycode =
+            keyboard
+                .get_keycode_by_phys(phys)
+                .ok_or_else(|| KeyboarderError::UnknownPhysKey {
+                    phys,
+                    layout: keyboard.get_active_layout_name(),
+                })?;
+        log::debug!("simulate phys {:?} => {:?}", phys, press);
+        self.simulate_keycode(keycode, press)
     }
 
-    fn simulate_keycode(&mut self, keycode: u32, press: bool) {
-        if let Err(err) = self.process_keycode_event_impl(keycode, press) {
-            log::error!("{err:#}")
-        };
+    fn simulate_keycode(&mut self, keycode: u32, press: bool) -> Result<()> {
+        self.process_keycode_event_impl(keycode, press)
     }
 
-    fn simulate_key_event(&mut self, key_event: &KeyEvent) {
-        if let Err(err) = self.process_key_event_impl(key_event) {
-            log::error!("{err:#}")
-        };
+    fn simulate_key_event(&mut self, key_event: &KeyEvent) -> Result<()> {
+        self.process_key_event_impl(key_event)
     }
 
-    fn simulate_server(&mut self, key_event: &KeyEvent) {
-        if let Err(err) = self.process_server_event_impl(key_event) {
-            log::error!("{err:#}")
-        };
+    fn simulate_server(&mut self, key_event: &KeyEvent) -> Result<()> {
+        self.isolated(|simulator| simulator.process_server_event_impl(key_event))
     }
 
-    fn release_modifiers(&mut self) -> anyhow::Result<()> {
-        let cur_modifiers = self.get_current_modifiers();
-        let target_modifiers = Modifiers::NONE;
-        let key_event_vec = cur_modifiers.diff_modifiers(&target_modifiers);
-
-        self.prepare_pressed_keys(&key_event_vec)?;
+    fn release_modifiers(&mut self) -> Result<()> {
+        self.sync_modifiers(Modifiers::NONE, false)
+    }
 
-        Ok(())
+    /// The modifiers stay held from one char to the next if they are needed,
+    /// they are released at the end.
+    fn type_text(&mut self, text: &str, options: &TypeOptions) -> Result<()> {
+        let chars: Vec<char> = text.chars().collect();
+        self.isolated(|simulator| {
+            let mut idx = 0;
+            while let Some(&chr) = chars.get(idx) {
+                if idx > 0 {
+                    simulator.pace(idx, options)?;
+                }
+                match chr {
+                    '\r' | '\n' => {
+                        if chr == '\r' && chars.get(idx + 1) == Some(&'\n') {
+                            idx += 1;
+                        }
+                        simulator.tap_without_modifiers(PhysKeyCode::Return)?;
+                    }
+                    '\t' => simulator.tap_without_modifiers(PhysKeyCode::Tab)?,
+                    _ => {
+                        if simulator.is_missing(chr) {
+                            simulator.remap_ahead(&chars[idx..], options.remap_batch)?;
+                        }
+                        simulator.process_char_impl(chr)?
+                    }
+                }
+                idx += 1;
+            }
+            simulator.release_modifiers()
+        })
     }
 }
 
 impl XSimulator {
     pub fn new(conn: &Rc<XConnection>) -> Self {
+        Self::with_device(conn, None)
+    }
+
+    /// The core key events of XTEST always go through the XTEST device of a
+    /// master keyboard, `device_id` selects the XKB state being changed.
+    pub fn new_for_device(conn: &Rc<XConnection>, device_id: u8) -> Self {
+        Self::with_device(conn, Some(device_id))
+    }
+
+    fn with_device(conn: &Rc<XConnection>, device_id: Option<u8>) -> Self {
         let root = conn.root;
-        let device_id = conn.keyboard.get_device_id();
 
         XSimulator {
             conn: Rc::downgrade(conn),
@@ -152,127 +157,388 @@ impl XSimulator {
             root,
             device_id,
             mode: None,
-            rebinding_keysyms: HashMap::new(),
+            remap_pool: RemapPool::default(),
+            sync_locks: false,
+            isolate_physical_mods: false,
+            original_internal_mods: None,
+            hidden_mods: Modifiers::NONE,
+            isolation_depth: 0,
         }
     }
 
     /// restore_flag is used to restore the keyboard state.
-    fn prepare_pressed_keys(&mut self, key_event_vec: &Vec<KeyEvent>) -> anyhow::Result<()> {
+    fn prepare_pressed_keys(&mut self, key_event_vec: &Vec<KeyEvent>) -> Result<()> {
         for key_event in key_event_vec {
-            if let KeyCode::Physical(phys) = key_event.key {
-                let press = key_event.press;
-                match key_event.press {
-                    true => self.simulate_phys(phys, press),
-                    false => self.simulate_phys(phys, press),
-                }
+            match key_event.key {
+                KeyCode::Physical(phys) => self.simulate_phys(phys, key_event.press)?,
+                // SUPER, HYPER and LEVEL5 have no physical key.
+                KeyCode::KeySym(keysym) => self.simulate_keysym(keysym, key_event.press)?,
+                _ => {}
             }
         }
         Ok(())
     }
 
-    fn rebinding_keycode(&self, keysym: u32) -> anyhow::Result<u32> {
-        let conn = &self.conn();
+    /// Locks are changed through XKB, and only if `sync_locks` is set. The
+    /// held modifiers are changed by pressing or releasing their keys, only
+    /// the keys pressed by the simulator are released.
+    fn sync_modifiers(&mut self, target_modifiers: Modifiers, sync_locks: bool) -> Result<()> {
+        let mut cur_modifiers = self.get_current_modifiers();
+        // Outside `isolated` nothing would give them back.
+        if self.isolate_physical_mods && self.isolation_depth > 0 {
+            cur_modifiers -= self.hide_physical_mods(target_modifiers)?;
+        }
+        let cur_modifiers = cur_modifiers | self.get_pressed_positional_mods(cur_modifiers);
+
+        let diff = ModifierDiff::new(cur_modifiers, target_modifiers);
+        if sync_locks && !diff.locks.is_empty() {
+            self.set_locks(target_modifiers & Modifiers::LOCKS)?;
+        }
+
+        let conn = self.conn();
+        let key_event_vec: Vec<KeyEvent> = diff
+            .key_events_with(|modifier, key| conn.keyboard.get_modifier_key(modifier, key))
+            .into_iter()
+            .filter(|key_event| key_event.press || self.is_key_down(&key_event.key))
+            .collect();
+        self.prepare_pressed_keys(&key_event_vec)
+    }
+
+    /// Explicitly lock or unlock CAPS, NUM and SCROLL.
+    pub fn set_locks(&self, locks: Modifiers) -> anyhow::Result<()> {
+        let conn = self.conn();
+        conn.keyboard.set_locks(&conn, locks & Modifiers::LOCKS)
+    }
+
+    fn tap_without_modifiers(&mut self, phys: PhysKeyCode) -> Result<()> {
+        self.sync_modifiers(Modifiers::NONE, false)?;
+        self.simulate_phys(phys, true)?;
+        self.simulate_phys(phys, false)
+    }
+
+    /// Wait between the chars of `type_text`, `typed` chars are already typed.
+    fn pace(&self, typed: usize, options: &TypeOptions) -> Result<()> {
+        if options.chunk_size > 0 && typed.is_multiple_of(options.chunk_size) {
+            self.conn().sync()?;
+            std::thread::sleep(options.chunk_delay);
+        } else if !options.key_delay.is_zero() {
+            std::thread::sleep(options.key_delay);
+        }
+        Ok(())
+    }
 
-        let mut unused = conn.keyboard.unused_keycodes.borrow_mut();
-        if unused.len() > 1 {
-            let keycode = unused.remove(0);
-            conn.send_request_no_reply_log(&xcb::x::ChangeKeyboardMapping {
-                keycode_count: 1,
-                first_keycode: keycode as u8,
-                keysyms_per_keycode: 1,
-                keysyms: &[keysym],
+    /// Run `f` as one sequence: the physical modifiers hidden while it types
+    /// come back at the end, without any key event.
+    pub fn isolated<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        self.isolation_depth += 1;
+        let res = f(self);
+        self.isolation_depth -= 1;
+
+        let restored = match self.isolation_depth {
+            0 => self.restore_physical_mods(),
+            _ => Ok(()),
+        };
+        res.and_then(|value| Ok(restored.map(|_| value)?))
+    }
+
+    /// The modifiers held on the physical keyboards: the ones of the keys
+    /// down but not pressed by the simulator.
+    pub fn get_physical_mods(&self) -> anyhow::Result<Modifiers> {
+        let conn = self.conn();
+        let reply = conn.send_and_wait_request(&xcb::x::QueryKeymap {})?;
+        let physical = (0..=u8::MAX)
+            .filter(|&keycode| reply.keys()[keycode as usize / 8] & (1 << (keycode % 8)) != 0)
+            .filter(|keycode| !self.pressed_key.contains(keycode))
+            .fold(Modifiers::NONE, |res, keycode| {
+                res | conn.keyboard.get_key_modifiers(keycode.into())
             });
-            conn.flush().context("flushing pending requests")?;
-            Ok(keycode)
-        } else {
-            anyhow::bail!("Can't find unused keycode");
+        Ok(physical - Modifiers::LOCKS)
+    }
+
+    /// The server keeps the physical modifiers missing from `target_modifiers`
+    /// as internal modifiers, the clients don't see them anymore.
+    fn hide_physical_mods(&mut self, target_modifiers: Modifiers) -> anyhow::Result<Modifiers> {
+        let hidden_mods = self.get_physical_mods()? - target_modifiers.trans_positional_mods();
+        if hidden_mods == self.hidden_mods {
+            return Ok(hidden_mods);
+        }
+
+        let conn = self.conn();
+        let original = match self.original_internal_mods {
+            Some(original) => original,
+            None => conn.keyboard.get_internal_mods(&conn)?,
+        };
+        self.original_internal_mods = Some(original);
+        let mask = conn.keyboard.get_modifier_map().to_mod_mask(hidden_mods);
+        conn.keyboard.set_internal_mods(&conn, original | mask)?;
+        log::debug!("Hide physical modifiers: {:?}", hidden_mods);
+
+        self.hidden_mods = hidden_mods;
+        Ok(hidden_mods)
+    }
+
+    fn restore_physical_mods(&mut self) -> anyhow::Result<()> {
+        let conn = self.conn();
+        self.restore_internal_mods(&conn)
+    }
+
+    fn restore_internal_mods(&mut self, conn: &XConnection) -> anyhow::Result<()> {
+        self.hidden_mods = Modifiers::NONE;
+        if let Some(original) = self.original_internal_mods.take() {
+            conn.keyboard.set_internal_mods(conn, original)?;
         }
+        Ok(())
     }
 
-    fn process_char_impl(&mut self, chr: char) -> anyhow::Result<()> {
+    fn is_key_down(&self, key: &KeyCode) -> bool {
+        let conn = self.conn();
+        let keycode = match *key {
+            KeyCode::Physical(phys) => conn.keyboard.get_keycode_by_phys(phys),
+            KeyCode::KeySym(keysym) => conn.keyboard.get_keycode_by_keysym(keysym),
+            KeyCode::RawCode(keycode) => Some(keycode),
+            _ => None,
+        };
+
+        keycode.is_some_and(|keycode| self.pressed_key.contains(&(keycode as u8)))
+    }
+
+    /// The sides of the `modifiers` held by the simulator, the server only
+    /// knows the modifiers. AltRight is not RIGHT_ALT when it is AltGr.
+    fn get_pressed_positional_mods(&self, modifiers: Modifiers) -> Modifiers {
+        [
+            (PhysKeyCode::ShiftLeft, Modifiers::LEFT_SHIFT),
+            (PhysKeyCode::ShiftRight, Modifiers::RIGHT_SHIFT),
+            (PhysKeyCode::ControlLeft, Modifiers::LEFT_CTRL),
+            (PhysKeyCode::ControlRight, Modifiers::RIGHT_CTRL),
+            (PhysKeyCode::AltLeft, Modifiers::LEFT_ALT),
+            (PhysKeyCode::AltRight, Modifiers::RIGHT_ALT),
+            (PhysKeyCode::MetaLeft, Modifiers::LEFT_META),
+            (PhysKeyCode::MetaRight, Modifiers::RIGHT_META),
+        ]
+        .into_iter()
+        .filter(|(_, side)| modifiers.contains(side.trans_positional_mods()))
+        .filter(|(phys, _)| self.is_key_down(&KeyCode::Physical(*phys)))
+        .fold(Modifiers::NONE, |res, (_, modifier)| res | modifier)
+    }
+
+    fn remap_keycode(&mut self, keysym: u32) -> Result<u8> {
+        let conn = self.conn();
+        let unused_keycodes = conn.keyboard.unused_keycodes.borrow().clone();
+        self.remap_pool
+            .remap(&conn, keysym, &unused_keycodes)?
+            .ok_or(KeyboarderError::OutOfSpareKeycodes(keysym))
+    }
+
+    /// Neither in the keymap, nor typed with a dead key or a Compose sequence,
+    /// nor in the remap pool.
+    fn is_missing(&self, chr: char) -> bool {
+        let keysym = char_to_keysym(chr);
+        let conn = self.conn();
+        !self.remap_pool.contains(keysym)
+            && conn.keyboard.get_plan_in_any_layout(keysym).is_none()
+            && conn
+                .keyboard
+                .get_dead_key_plans(chr, Modifiers::NONE)
+                .is_none()
+            && conn
+                .keyboard
+                .get_compose_plans(chr, Modifiers::NONE)
+                .is_none()
+    }
+
+    /// Remap the next missing keysyms of `chars` at once, then wait once for
+    /// the new mapping instead of once per char.
+    fn remap_ahead(&mut self, chars: &[char], batch: usize) -> Result<()> {
+        let mut keysyms = vec![];
+        for &chr in chars.iter().filter(|chr| !chr.is_control()) {
+            if keysyms.len() >= batch {
+                break;
+            }
+            let keysym = char_to_keysym(chr);
+            if !keysyms.contains(&keysym) && self.is_missing(chr) {
+                keysyms.push(keysym);
+            }
+        }
+        // A single keysym is remapped by `process_char_impl`.
+        if keysyms.len() < 2 {
+            return Ok(());
+        }
+
+        let conn = self.conn();
+        let unused_keycodes = conn.keyboard.unused_keycodes.borrow().clone();
+        let num = self
+            .remap_pool
+            .remap_batch(&conn, &keysyms, &unused_keycodes)?;
+        log::info!("Remapping {num} keysyms at once");
+
+        conn.sync()?;
+        conn.process_queued_xcb()?;
+        Ok(())
+    }
+
+    /// Give back the keycodes of the remap pool with their original mapping.
+    pub fn restore_keymap(&mut self) -> Result<()> {
+        let conn = self.conn();
+        Ok(self.remap_pool.restore(&conn)?)
+    }
+
+    fn process_char_impl(&mut self, chr: char) -> Result<()> {
         let keysym = keysyms::char_to_keysym(chr);
 
+        // Once remapped, the keysym is also in the reloaded keymap.
+        if let Some(keycode) = self.remap_pool.get(keysym) {
+            return self.tap_remapped(keycode);
+        }
+
+        // The plan works with the locks of the user instead of changing them,
+        // without `sync_locks` the plans toggling NUM are left out.
+        let locks = self.get_current_modifiers() & Modifiers::LOCKS;
         let conn = &self.conn();
         let keyboard = &conn.keyboard;
-        let char_key_event = keyboard.get_key_event_by_keysym(keysym);
-
-        if let Some(char_key_event) = char_key_event {
-            let target_modifiers = char_key_event.modifiers;
-            let cur_modifiers = self.get_current_modifiers();
-            let key_event_vec = cur_modifiers.diff_modifiers(&target_modifiers);
-            self.prepare_pressed_keys(&key_event_vec)?;
-
-            if let KeyCode::RawCode(keycode) = char_key_event.key {
-                self.simulate_keycode(keycode, true);
+        let keeps_locks = |plan: &KeysymPlan| self.sync_locks || plan.locks.is_none();
+        let plan = keyboard
+            .get_plan_with_locks(keysym, locks)
+            .filter(keeps_locks);
+
+        if let Some(plan) = plan {
+            self.process_plan_in_group(&plan)?;
+        } else if let Some(plans) = keyboard
+            .get_dead_key_plans(chr, locks)
+            .filter(|plans| plans.iter().all(keeps_locks))
+        {
+            log::debug!("Compose {:?} with a dead key", chr);
+            for plan in plans {
+                self.process_plan_in_group(&plan)?;
             }
-            if let KeyCode::RawCode(keycode) = char_key_event.key {
-                self.simulate_keycode(keycode, false);
+        } else if let Some(plans) = keyboard
+            .get_compose_plans(chr, locks)
+            .filter(|plans| plans.iter().all(keeps_locks))
+        {
+            log::debug!("Compose {:?} with {} keys", chr, plans.len());
+            for plan in plans {
+                self.process_plan_in_group(&plan)?;
             }
-        } else if let Some(&keycode) = self.rebinding_keysyms.get(&keysym) {
-            self.release_modifiers()?;
-            self.simulate_keycode(keycode, true);
-            self.simulate_keycode(keycode, false);
-        } else if let Ok(keycode) = self.rebinding_keycode(keysym) {
+        } else {
+            let keycode = self.remap_keycode(keysym)?;
             log::info!(
                 "Remapping keycode={keycode} => keysym={:?} char={:?}')",
                 keysym,
                 chr
             );
-            self.rebinding_keysyms.insert(keysym, keycode);
+            self.tap_remapped(keycode)?;
+        }
 
-            self.release_modifiers()?;
-            self.simulate_keycode(keycode, true);
-            self.simulate_keycode(keycode, false);
-        } else {
-            anyhow::bail!("Failed to process char: char={chr}, keysym={keysym}")
+        Ok(())
+    }
+
+    fn tap_remapped(&mut self, keycode: u8) -> Result<()> {
+        self.release_modifiers()?;
+        self.simulate_keycode(keycode.into(), true)?;
+        self.simulate_keycode(keycode.into(), false)
+    }
+
+    /// Switch to the layout of the plan for the time of the plan.
+    fn process_plan_in_group(&mut self, plan: &KeysymPlan) -> Result<()> {
+        let plan_group = GroupIndex::from(plan.layout);
+        // The group tracked from StateNotify, the server is only asked
+        // before a switch.
+        if plan_group == self.conn().keyboard.get_active_group_index() {
+            return self.process_plan_with_locks(plan);
+        }
+
+        let (locked_group, latched_group) = self.get_group_state()?;
+        log::debug!("Switch group {:?} => {:?}", locked_group, plan_group);
+        // A latched group would be added to the locked one.
+        self.set_group_state(plan_group, 0)?;
+
+        let res = self.process_plan_with_locks(plan);
+
+        self.set_group_state(locked_group, latched_group)?;
+        res
+    }
+
+    /// Set the locks of the plan for the time of the plan, such plans are
+    /// only picked with `sync_locks`.
+    fn process_plan_with_locks(&mut self, plan: &KeysymPlan) -> Result<()> {
+        let Some(locks) = plan.locks else {
+            return self.process_plan_impl(plan);
+        };
+        let user_locks = self.get_current_modifiers() & Modifiers::LOCKS;
+        log::debug!("Switch locks {:?} => {:?}", user_locks, locks);
+        self.set_locks(locks)?;
+
+        let res = self.process_plan_impl(plan);
+
+        self.set_locks(user_locks)?;
+        res
+    }
+
+    fn process_plan_impl(&mut self, plan: &KeysymPlan) -> Result<()> {
+        self.sync_modifiers(plan.modifiers, false)?;
+
+        for key_event in plan.key_events() {
+            self.simulate_key_event(&key_event)?;
         }
 
         Ok(())
     }
 
+    /// The locked and the latched groups. Ask the server, the keyboard may not
+    /// have processed the StateNotify yet.
+    fn get_group_state(&self) -> anyhow::Result<(GroupIndex, i16)> {
+        let reply = self.conn().send_and_wait_request(&xcb::xkb::GetState {
+            device_spec: self.device_id().into(),
+        })?;
+
+        Ok((
+            GroupIndex::from(reply.locked_group()),
+            reply.latched_group(),
+        ))
+    }
+
+    /// The StateNotify caused by this only switches the active plans of the
+    /// keyboard, see `XKeyboard::update_group`.
+    fn set_group_state(&self, locked_group: GroupIndex, latched_group: i16) -> anyhow::Result<()> {
+        self.conn().send_request_checked(&xcb::xkb::LatchLockState {
+            device_spec: self.device_id().into(),
+            affect_mod_locks: xcb::x::ModMask::empty(),
+            mod_locks: xcb::x::ModMask::empty(),
+            lock_group: true,
+            group_lock: locked_group.into(),
+            affect_mod_latches: xcb::x::ModMask::empty(),
+            latch_group: true,
+            // The server reads it back as signed.
+            group_latch: latched_group as u16,
+        })
+    }
+
     /// https://stackoverflow.com/questions/69656145/how-does-modifiersas-in-xmodmap-work-under-linux-operating-system
     /// Use xmodmap -pm to get meaning of modifier
     ///
-    /// simulate key will not send xkb event to update state. so we should get new state.
+    /// The fake inputs are checked, so the StateNotify they caused are already
+    /// queued: process them before reading the state of the keyboard.
     pub fn get_current_modifiers(&self) -> Modifiers {
+        self.get_modifier_state().effective()
+    }
+
+    pub fn get_modifier_state(&self) -> ModifierState {
         let conn = self.conn();
-        let kbd = &conn.keyboard;
-
-        let keymap = &conn.keyboard.keymap.borrow();
-        let device_id = kbd.get_device_id();
-        let state = xkb::x11::state_new_from_device(keymap, &conn, device_id.into());
-
-        let mut res = Modifiers::default();
-        for (mod_name, modifier) in [
-            (xkb::MOD_NAME_SHIFT, Modifiers::SHIFT),
-            (xkb::MOD_NAME_CTRL, Modifiers::CTRL),
-            (xkb::MOD_NAME_ALT, Modifiers::ALT),
-            (xkb::MOD_NAME_LOGO, Modifiers::META),
-            (xkb::MOD_NAME_CAPS, Modifiers::CAPS),
-            (xkb::MOD_NAME_NUM, Modifiers::NUM),
-            (MOD_NAME_ISO_LEVEL3_SHIFT, Modifiers::ALT_GR),
-        ] {
-            if state.mod_name_is_active(mod_name, xkb::STATE_MODS_EFFECTIVE) {
-                res |= modifier;
-            }
+        if let Err(err) = conn.process_queued_xcb() {
+            log::error!("{err:#}");
         }
 
-        res
+        conn.keyboard.get_modifier_state()
     }
 
-    fn process_server_event_impl(&mut self, key_event: &KeyEvent) -> anyhow::Result<()> {
-        let mode = if let Some(mode) = &self.mode {
-            mode
-        } else {
-            anyhow::bail!("Can't find simulate mode");
-        };
+    fn process_server_event_impl(&mut self, key_event: &KeyEvent) -> Result<()> {
+        let mode = self.mode.as_ref().ok_or(KeyboarderError::ModeNotSet)?;
         let conn = self.conn();
         let press = key_event.press;
 
         match mode {
             ServerMode::Map => {
                 if let Some(raw_event) = key_event.raw_event {
-                    self.simulate_phys(raw_event.key, press)
+                    self.simulate_phys(raw_event.key, press)?;
                 }
             }
             ServerMode::Translate => {
@@ -280,8 +546,7 @@ impl XSimulator {
                 let char_keysym = kbd.char_keysym.borrow();
 
                 let cur_modifiers = self.get_current_modifiers();
-                let target_modifers = key_event.modifiers.trans_positional_mods();
-                let key_event_vec = cur_modifiers.diff_modifiers(&target_modifers);
+                let target_modifers = key_event.modifiers;
 
                 match key_event.key {
                     KeyCode::Char(chr) => {
@@ -293,30 +558,33 @@ impl XSimulator {
                             // Fr:
                             // "!" => keycode=33, but shift + 1 is US
                             // exclude: delete(\u{8})
-                            self.simulate_char_without_modifiers(chr);
+                            self.simulate_char_without_modifiers(chr)?;
                         } else if chr.is_control() {
                             // PhysKeyCode: \u{8} => Delete( chr is )
                             if let Some(&keysym) = char_keysym.get(&(chr as u32)) {
-                                self.prepare_pressed_keys(&key_event_vec)?;
+                                self.sync_modifiers(target_modifers, self.sync_locks)?;
 
-                                self.simulate_keysym(keysym, true);
-                                self.simulate_keysym(keysym, false);
+                                self.simulate_keysym(keysym, true)?;
+                                self.simulate_keysym(keysym, false)?;
                             } else {
-                                log::error!("Faile to process control char: {:?}", chr);
+                                return Err(KeyboarderError::NoKeycodeForKeysym {
+                                    keysym,
+                                    layout: kbd.get_active_layout_name(),
+                                });
                             }
                         } else if kbd.keysym_keycode_map.borrow().contains_key(&keysym) {
                             // PhysKeyCode: q => KeyQ in US, q => keyA(Input char "a") in Fr
-                            self.prepare_pressed_keys(&key_event_vec)?;
+                            self.sync_modifiers(target_modifers, self.sync_locks)?;
 
-                            self.simulate_keysym(keysym, true);
-                            self.simulate_keysym(keysym, false);
+                            self.simulate_keysym(keysym, true)?;
+                            self.simulate_keysym(keysym, false)?;
                         } else {
-                            self.simulate_char_without_modifiers(chr);
+                            self.simulate_char_without_modifiers(chr)?;
                         }
                     }
-                    KeyCode::Physical(phys) => self.simulate_phys(phys, press),
+                    KeyCode::Physical(phys) => self.simulate_phys(phys, press)?,
                     KeyCode::RawCode(_) => {
-                        log::error!("Unexcept key event: {:?}", key_event);
+                        return Err(KeyboarderError::UnexpectedKeyEvent(key_event.clone()));
                     }
                     _ => {}
                 }
@@ -327,26 +595,21 @@ impl XSimulator {
         Ok(())
     }
 
-    fn process_key_event_impl(&mut self, key_event: &KeyEvent) -> anyhow::Result<()> {
+    fn process_key_event_impl(&mut self, key_event: &KeyEvent) -> Result<()> {
         match key_event.key {
             KeyCode::RawCode(keycode) => self.simulate_keycode(keycode, key_event.press),
             KeyCode::KeySym(keysym) => self.simulate_keysym(keysym, key_event.press),
             KeyCode::Physical(phys) => self.simulate_phys(phys, key_event.press),
 
-            _ => {}
+            _ => Ok(()),
         }
-
-        Ok(())
     }
 
-    fn process_keycode_event_impl(&mut self, keycode: u32, press: bool) -> anyhow::Result<()> {
-        if !(8..=255).contains(&keycode) {
-            anyhow::bail!(
-                "Unexpected keycode, keycode should in (8, 255): keycode={:?}",
-                keycode
-            );
-        }
-        let keycode: u8 = keycode.try_into()?;
+    fn process_keycode_event_impl(&mut self, keycode: u32, press: bool) -> Result<()> {
+        let keycode = match u8::try_from(keycode) {
+            Ok(keycode) if keycode >= 8 => keycode,
+            _ => return Err(KeyboarderError::InvalidKeycode(keycode)),
+        };
 
         match press {
             true => self.pressed_key.insert(keycode),
@@ -357,21 +620,25 @@ impl XSimulator {
     }
 
     fn send_native(&self, keycode: u8, press: bool) -> anyhow::Result<()> {
+        self.send_native_with(&self.conn(), keycode, press)
+    }
+
+    fn send_native_with(&self, conn: &XConnection, keycode: u8, press: bool) -> anyhow::Result<()> {
         let r#type = match press {
             true => XCB_KEY_PRESS,
             false => XCB_KEY_RELEASE,
         };
-        let conn = self.conn();
-        conn.send_request_no_reply_log(&xcb::xtest::FakeInput {
+        conn.send_request_checked(&xcb::xtest::FakeInput {
             r#type,
             detail: keycode,
             time: 0,
             root: self.root,
             root_x: 0,
             root_y: 0,
-            deviceid: self.device_id,
-        });
-        conn.flush().context("flushing pending requests")?;
+            deviceid: self
+                .device_id
+                .unwrap_or_else(|| conn.keyboard.get_device_id()),
+        })?;
         log::trace!(
             "simulate keycode {:?}({:?}) -> {:?}",
             keycode,
@@ -382,27 +649,51 @@ impl XSimulator {
         anyhow::Ok(())
     }
 
+    fn device_id(&self) -> u8 {
+        self.device_id
+            .unwrap_or_else(|| self.conn().keyboard.get_device_id())
+    }
+
     fn conn(&self) -> Rc<XConnection> {
         self.conn.upgrade().expect("XConnection to be alive")
     }
 
-    fn release_pressed_keys(&mut self) {
+    /// Release all the keys pressed by the simulator, the modifiers first.
+    pub fn release_keys(&mut self) -> Result<()> {
+        self.release_modifiers()?;
+        self.release_pressed_keys(&self.conn());
+        Ok(())
+    }
+
+    fn release_pressed_keys(&mut self, conn: &XConnection) {
         if !self.pressed_key.is_empty() {
             log::debug!("Auto release key: {:?}", self.pressed_key);
             for keycode in &self.pressed_key {
-                if let Err(err) = self.send_native(*keycode, false) {
+                if let Err(err) = self.send_native_with(conn, *keycode, false) {
                     log::error!("{err:#}")
                 };
             }
             self.pressed_key.clear();
         }
     }
+
+    /// Release the keys and give back the remapped keycodes. The connection
+    /// is given because the simulator may be dropped with it.
+    pub(crate) fn cleanup(&mut self, conn: &XConnection) {
+        self.release_pressed_keys(conn);
+        if let Err(err) = self.restore_internal_mods(conn) {
+            log::error!("Failed to restore the physical modifiers: {err:#}");
+        }
+        if let Err(err) = self.remap_pool.restore(&conn.conn) {
+            log::error!("Failed to restore the remapped keycodes: {err:#}");
+        }
+    }
 }
 
 impl Drop for XSimulator {
     fn drop(&mut self) {
-        self.release_pressed_keys();
-        // FIXME
-        // self.release_modifiers().ma;
+        if let Some(conn) = self.conn.upgrade() {
+            self.cleanup(&conn);
+        }
     }
 }
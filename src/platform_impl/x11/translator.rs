@@ -0,0 +1,125 @@
+use super::keyboard::XKeyboard;
+
+use crate::types::{KeyCode, KeyEvent, Modifiers, PhysKeyCode};
+
+use std::{cell::RefCell, collections::HashMap};
+
+/// Translate the key events of a source layout into the key events producing
+/// the same chars on a target layout.
+///
+/// It only looks at the two keymaps, both keyboards can be built offline.
+pub struct LayoutTranslator {
+    source: XKeyboard,
+    target: XKeyboard,
+    /// What was pressed on the target, so that a release releases the same
+    /// keys even if the modifiers changed meanwhile.
+    pressed: RefCell<HashMap<PhysKeyCode, Vec<KeyEvent>>>,
+}
+
+impl LayoutTranslator {
+    pub fn new(source: XKeyboard, target: XKeyboard) -> Self {
+        Self {
+            source,
+            target,
+            pressed: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn source(&self) -> &XKeyboard {
+        &self.source
+    }
+
+    pub fn target(&self) -> &XKeyboard {
+        &self.target
+    }
+
+    /// Translate a physical key event of the source layout.
+    ///
+    /// Printing keys are typed with the target key producing the same char,
+    /// the modifiers not consumed by the source (e.g. CTRL) are kept.
+    /// Non-printing keys fall back to the same physical key. A char missing
+    /// in the target layout gives a `KeyCode::Char` event.
+    pub fn translate(&self, key_event: &KeyEvent) -> anyhow::Result<Vec<KeyEvent>> {
+        let phys = match (&key_event.key, key_event.raw_event) {
+            (KeyCode::Physical(phys), _) => *phys,
+            (_, Some(raw_event)) => raw_event.key,
+            _ => anyhow::bail!("Not a physical key event: {:?}", key_event),
+        };
+
+        if !key_event.press {
+            if let Some(pressed) = self.pressed.borrow_mut().remove(&phys) {
+                return Ok(with_press(pressed, false));
+            }
+        }
+
+        let key_event_vec = self.translate_press(phys, key_event.modifiers)?;
+        if key_event.press {
+            self.pressed
+                .borrow_mut()
+                .insert(phys, key_event_vec.clone());
+        }
+        Ok(with_press(key_event_vec, key_event.press))
+    }
+
+    fn translate_press(
+        &self,
+        phys: PhysKeyCode,
+        modifiers: Modifiers,
+    ) -> anyhow::Result<Vec<KeyEvent>> {
+        let modifiers = modifiers.trans_positional_mods();
+        let source_keycode = self
+            .source
+            .get_keycode_by_phys(phys)
+            .ok_or_else(|| anyhow::anyhow!("No PhysKeyCode {:?} in source layout", phys))?;
+
+        let (keysym, unconsumed) = self
+            .source
+            .get_keysym_by_modifiers(source_keycode, modifiers);
+        let chr = char::from_u32(xkbcommon::xkb::keysym_to_utf32(keysym))
+            .filter(|chr| *chr != '\0' && !chr.is_control());
+
+        let chr = match chr {
+            Some(chr) => chr,
+            None => return self.translate_phys(phys, modifiers),
+        };
+
+        if let Some(plan) = self.target.get_plan_by_keysym(keysym) {
+            let locks = Modifiers::LOCKS;
+            let mut key_event = plan.key_event(true);
+            key_event.modifiers = plan.modifiers | (unconsumed - locks);
+            Ok(vec![key_event])
+        } else {
+            Ok(vec![KeyEvent {
+                key: KeyCode::Char(chr),
+                press: true,
+                modifiers: unconsumed,
+                raw_event: None,
+            }])
+        }
+    }
+
+    fn translate_phys(
+        &self,
+        phys: PhysKeyCode,
+        modifiers: Modifiers,
+    ) -> anyhow::Result<Vec<KeyEvent>> {
+        let keycode = self
+            .target
+            .get_keycode_by_phys(phys)
+            .ok_or_else(|| anyhow::anyhow!("No PhysKeyCode {:?} in target layout", phys))?;
+
+        Ok(vec![KeyEvent {
+            key: KeyCode::RawCode(keycode),
+            press: true,
+            modifiers,
+            raw_event: None,
+        }])
+    }
+}
+
+fn with_press(key_event_vec: Vec<KeyEvent>, press: bool) -> Vec<KeyEvent> {
+    key_event_vec
+        .into_iter()
+        .map(|key_event| KeyEvent { press, ..key_event })
+        .collect()
+}
@@ -1,30 +1,156 @@
+use crate::error::Result;
 use crate::types::{KeyEvent, PhysKeyCode};
-use crate::types::{ServerMode, SimEvent};
-use filedescriptor::FileDescriptor;
-use std::sync::Arc;
+use crate::types::{ServerMode, SimEvent, TypeOptions};
+use anyhow::Context;
+use filedescriptor::{FileDescriptor, Pipe};
+use std::io::Write;
 use std::sync::Mutex;
 use std::thread::JoinHandle;
 
-lazy_static::lazy_static! {
-    pub static ref  SENDER: Arc<Mutex<Option<FileDescriptor>>> = Default::default();
+/// The failures are returned to the caller, see `KeyboarderError`.
+pub trait Simulate {
+    /// Simulate the events sent through the returned handle in a new thread.
+    fn spawn_server(mode: ServerMode) -> Result<SimulatorHandle>;
+
+    fn simulate_keycode(&mut self, keycode: u32, press: bool) -> Result<()>;
+
+    fn simulate_keysym(&mut self, keysym: u32, press: bool) -> Result<()>;
+
+    fn simulate_char_without_modifiers(&mut self, chr: char) -> Result<()>;
+
+    fn simulate_phys(&mut self, phys: PhysKeyCode, press: bool) -> Result<()>;
+
+    fn simulate_key_event(&mut self, key_event: &KeyEvent) -> Result<()>;
+
+    fn simulate_server(&mut self, key_event: &KeyEvent) -> Result<()>;
+
+    fn release_modifiers(&mut self) -> Result<()>;
+
+    /// Type `text`, "\n", "\r\n" and "\t" are typed with Return and Tab.
+    fn type_text(&mut self, text: &str, options: &TypeOptions) -> Result<()>;
 }
 
-pub trait Simulate {
-    fn spawn_server(mode: ServerMode) -> anyhow::Result<JoinHandle<()>>;
+/// The owner of a simulator thread. Dropping it releases the held keys and
+/// stops the thread.
+pub struct SimulatorHandle {
+    writer: Mutex<FileDescriptor>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl SimulatorHandle {
+    pub fn new(writer: FileDescriptor, thread: JoinHandle<()>) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+            thread: Mutex::new(Some(thread)),
+        }
+    }
 
-    fn event_to_server(event: &SimEvent) -> anyhow::Result<()>;
+    /// Run `message_loop` in a new thread, it reads the events from the
+    /// non-blocking fd it is given, see `FrameDecoder`.
+    pub fn spawn(message_loop: impl FnOnce(FileDescriptor) + Send + 'static) -> Result<Self> {
+        let pipe = Pipe::new().context("creating the simulate pipe")?;
 
-    fn simulate_keycode(&mut self, keycode: u32, press: bool);
+        // Only the reader is polled, the writer blocks until the whole
+        // message is written.
+        let mut read_fd = pipe.read;
+        read_fd
+            .set_non_blocking(true)
+            .context("setting the pipe non-blocking")?;
 
-    fn simulate_keysym(&mut self, keysym: u32, press: bool);
+        let thread = std::thread::spawn(move || message_loop(read_fd));
+        Ok(Self::new(pipe.write, thread))
+    }
 
-    fn simulate_char_without_modifiers(&mut self, chr: char);
+    pub fn send(&self, event: &SimEvent) -> Result<()> {
+        let frame = encode_frame(event)?;
+        let mut writer = self.writer.lock().unwrap();
+        writer
+            .write_all(&frame)
+            .context("writing to the simulator thread")?;
+        Ok(())
+    }
 
-    fn simulate_phys(&mut self, phys: PhysKeyCode, press: bool);
+    pub fn simulate(&self, key_event: KeyEvent) -> Result<()> {
+        self.send(&SimEvent::Simulate(key_event))
+    }
+
+    pub fn type_text(&self, text: &str, options: TypeOptions) -> Result<()> {
+        self.send(&SimEvent::TypeText {
+            text: text.to_owned(),
+            options,
+        })
+    }
+
+    pub fn release_keys(&self) -> Result<()> {
+        self.send(&SimEvent::ReleaseKeys)
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.thread
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Release the held keys, stop the thread and wait for it.
+    pub fn shutdown(&self) -> Result<()> {
+        let thread = match self.thread.lock().unwrap().take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+        // The thread may already be gone, join it anyway.
+        let sent = self.send(&SimEvent::ExitThread);
+        if thread.join().is_err() {
+            log::error!("The simulator thread panicked");
+        }
+        sent
+    }
+}
+
+impl Drop for SimulatorHandle {
+    fn drop(&mut self) {
+        if let Err(err) = self.shutdown() {
+            log::error!("Failed to stop the simulator thread: {err:#}");
+        }
+    }
+}
+
+/// A message is the length of the event as a little endian u32, then the
+/// event.
+pub fn encode_frame(event: &SimEvent) -> anyhow::Result<Vec<u8>> {
+    let payload: Vec<u8> = event.clone().try_into()?;
+    let len = u32::try_from(payload.len()).context("event too large")?;
+
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Split the bytes read from the pipe into events, whatever the size of the
+/// reads.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
 
-    fn simulate_key_event(&mut self, key_event: &KeyEvent);
+impl FrameDecoder {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
 
-    fn simulate_server(&mut self, key_event: &KeyEvent);
+    /// The next complete event, None until all of its bytes are pushed.
+    pub fn next_event(&mut self) -> anyhow::Result<Option<SimEvent>> {
+        let Some(len) = self.buf.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        if self.buf.len() < 4 + len {
+            return Ok(None);
+        }
 
-    fn release_modifiers(&mut self) -> anyhow::Result<()>;
+        let payload: Vec<u8> = self.buf.drain(..4 + len).skip(4).collect();
+        Ok(Some(SimEvent::try_from(payload)?))
+    }
 }
@@ -158,12 +158,13 @@ bitflags! {
     /// https://stackoverflow.com/questions/69656145/how-does-modifiersas-in-xmodmap-work-under-linux-operating-system
     /// Use xmodmap -pm to get meaning of modifier
     #[derive(Default, Deserialize, Serialize)]
-    pub struct Modifiers: u16 {
+    pub struct Modifiers: u32 {
         const NONE = 0;
 
         const SHIFT = 1<<1;
         const ALT = 1<<2;
         const CTRL = 1<<3;
+        /// The logo key, MetaLeft and MetaRight.
         const META = 1<<4;
 
         const LEFT_ALT = 1<<5;
@@ -177,6 +178,14 @@ bitflags! {
         const NUM = 1<<12;
 
         const ALT_GR = 1<<13;
+
+        const SUPER = 1<<14;
+        const HYPER = 1<<15;
+        const LEVEL5 = 1<<16;
+        const SCROLL = 1<<17;
+
+        const LEFT_META = 1<<18;
+        const RIGHT_META = 1<<19;
     }
 }
 
@@ -202,6 +211,12 @@ impl TryFrom<String> for Modifiers {
             (Self::CAPS, "CAPS"),
             (Self::NUM, "NUM"),
             (Self::ALT_GR, "ALT_GR"),
+            (Self::SUPER, "SUPER"),
+            (Self::HYPER, "HYPER"),
+            (Self::LEVEL5, "LEVEL5"),
+            (Self::SCROLL, "SCROLL"),
+            (Self::LEFT_META, "LEFT_META"),
+            (Self::RIGHT_META, "RIGHT_META"),
         ] {
             if modifier_vec.contains(&label) {
                 mods |= value;
@@ -262,6 +277,12 @@ impl ToString for Modifiers {
             (Self::CAPS, "CAPS"),
             (Self::NUM, "NUM"),
             (Self::ALT_GR, "ALT_GR"),
+            (Self::SUPER, "SUPER"),
+            (Self::HYPER, "HYPER"),
+            (Self::LEVEL5, "LEVEL5"),
+            (Self::SCROLL, "SCROLL"),
+            (Self::LEFT_META, "LEFT_META"),
+            (Self::RIGHT_META, "RIGHT_META"),
         ] {
             if !self.contains(value) {
                 continue;
@@ -277,6 +298,11 @@ impl ToString for Modifiers {
 }
 
 impl Modifiers {
+    /// Toggled by their keys instead of held.
+    pub const LOCKS: Modifiers = Modifiers::from_bits_truncate(
+        Modifiers::CAPS.bits() | Modifiers::NUM.bits() | Modifiers::SCROLL.bits(),
+    );
+
     /// Remove positional and other "supplemental" bits that
     /// are used to carry around implementation details, but that
     /// are not bits that should be matched when matching key
@@ -287,7 +313,9 @@ impl Modifiers {
             | Self::LEFT_CTRL
             | Self::RIGHT_CTRL
             | Self::LEFT_SHIFT
-            | Self::RIGHT_SHIFT)
+            | Self::RIGHT_SHIFT
+            | Self::LEFT_META
+            | Self::RIGHT_META)
     }
 
     pub fn trans_positional_mods(self) -> Self {
@@ -297,6 +325,7 @@ impl Modifiers {
             (Self::ALT, (Self::LEFT_ALT, Self::RIGHT_ALT)),
             (Self::CTRL, (Self::LEFT_CTRL, Self::RIGHT_CTRL)),
             (Self::SHIFT, (Self::LEFT_SHIFT, Self::RIGHT_SHIFT)),
+            (Self::META, (Self::LEFT_META, Self::RIGHT_META)),
         ] {
             if self.contains(left_mod) || self.contains(right_mod) {
                 modifiers = modifiers - left_mod - right_mod;
@@ -306,69 +335,15 @@ impl Modifiers {
         modifiers
     }
 
-    /// todo!: return extra_modifiers, missing_modifiers
-    /// FIXME: linux & windows impl
-    ///
-    ///  Get the codes that should be clicked,
-    /// modifiers of both side can be sync after clicking the keys.
-    ///
-    /// The modifers in the vec represent the active state of the remote modifier,
-    /// compare it with the local modifiers.
+    /// Get the key events turning these modifiers into `modifiers`, see
+    /// `ModifierDiff`.
     pub fn diff_modifiers(&self, modifiers: &Modifiers) -> Vec<KeyEvent> {
-        let mut key_event_vec: Vec<KeyEvent> = vec![];
-        let target_modifiers = modifiers;
-
-        for (modifier, phys) in [
-            (Modifiers::CAPS, PhysKeyCode::CapsLock),
-            (Modifiers::NUM, PhysKeyCode::NumLock),
-        ] {
-            let pressed = target_modifiers.contains(modifier);
-
-            if pressed && !self.contains(modifier) || !pressed && self.contains(modifier) {
-                key_event_vec.push(KeyEvent::with_phys(phys, true));
-                key_event_vec.push(KeyEvent::with_phys(phys, false));
-            }
-            continue;
-        }
-
-        for (modifier, left_phys, right_phys) in [
-            (
-                Modifiers::SHIFT,
-                PhysKeyCode::ShiftLeft,
-                PhysKeyCode::ShiftRight,
-            ),
-            (
-                Modifiers::CTRL,
-                PhysKeyCode::ControlLeft,
-                PhysKeyCode::ControlRight,
-            ),
-            (Modifiers::ALT, PhysKeyCode::AltLeft, PhysKeyCode::AltRight),
-            (
-                Modifiers::META,
-                PhysKeyCode::MetaLeft,
-                PhysKeyCode::MetaRight,
-            ),
-            (
-                Modifiers::ALT_GR,
-                PhysKeyCode::AltRight,
-                PhysKeyCode::AltRight,
-            ),
-        ] {
-            let pressed = target_modifiers.contains(modifier);
-
-            if !pressed && self.contains(modifier) {
-                key_event_vec.push(KeyEvent::with_phys(left_phys, false));
-                key_event_vec.push(KeyEvent::with_phys(right_phys, false));
-            }
-            if pressed && !self.contains(modifier) {
-                key_event_vec.push(KeyEvent::with_phys(left_phys, true))
-            }
-        }
+        let key_event_vec = ModifierDiff::new(*self, *modifiers).key_events();
 
         log::trace!(
             "cur_modifier={:?}, target_modifier={:?} => key_event_vec={:?}",
             self,
-            target_modifiers,
+            modifiers,
             key_event_vec
         );
 
@@ -384,6 +359,10 @@ impl Modifiers {
             Modifiers::RIGHT_CTRL,
             Modifiers::RIGHT_ALT,
             Modifiers::META,
+            Modifiers::LEFT_META,
+            Modifiers::RIGHT_META,
+            Modifiers::SUPER,
+            Modifiers::HYPER,
         ] {
             if self.contains(mods) {
                 return true;
@@ -393,6 +372,173 @@ impl Modifiers {
     }
 }
 
+/// The held modifiers having a key on each side.
+const SIDED_MODIFIERS: [(Modifiers, Modifiers, Modifiers, PhysKeyCode, PhysKeyCode); 4] = [
+    (
+        Modifiers::SHIFT,
+        Modifiers::LEFT_SHIFT,
+        Modifiers::RIGHT_SHIFT,
+        PhysKeyCode::ShiftLeft,
+        PhysKeyCode::ShiftRight,
+    ),
+    (
+        Modifiers::CTRL,
+        Modifiers::LEFT_CTRL,
+        Modifiers::RIGHT_CTRL,
+        PhysKeyCode::ControlLeft,
+        PhysKeyCode::ControlRight,
+    ),
+    (
+        Modifiers::ALT,
+        Modifiers::LEFT_ALT,
+        Modifiers::RIGHT_ALT,
+        PhysKeyCode::AltLeft,
+        PhysKeyCode::AltRight,
+    ),
+    (
+        Modifiers::META,
+        Modifiers::LEFT_META,
+        Modifiers::RIGHT_META,
+        PhysKeyCode::MetaLeft,
+        PhysKeyCode::MetaRight,
+    ),
+];
+
+/// No physical key, they only exist in the keymaps of X11.
+const KEYSYM_MODIFIERS: [(Modifiers, KeySym); 3] = [
+    (Modifiers::SUPER, crate::keysyms::KEY_SUPER_L),
+    (Modifiers::HYPER, crate::keysyms::KEY_HYPER_L),
+    (Modifiers::LEVEL5, crate::keysyms::KEY_ISO_LEVEL5_SHIFT),
+];
+
+/// The side bits of a held modifier, or the modifier itself if the side is
+/// unknown.
+fn modifier_sides(modifiers: Modifiers, modifier: Modifiers, sides: Modifiers) -> Modifiers {
+    match modifiers & sides {
+        Modifiers::NONE if modifiers.contains(modifier) => modifier,
+        sides => sides,
+    }
+}
+
+/// What separates the current modifiers from the target ones. The LEFT_ and
+/// RIGHT_ bits are kept when the side is known, a modifier without side is
+/// satisfied by either key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ModifierDiff {
+    /// Held but not in the target.
+    pub extra: Modifiers,
+    /// In the target but not held.
+    pub missing: Modifiers,
+    /// Locks to toggle, through `XKeyboard::set_locks`: which keys toggle
+    /// them depends on the keymap.
+    pub locks: Modifiers,
+}
+
+impl ModifierDiff {
+    pub fn new(current: Modifiers, target: Modifiers) -> Self {
+        let mut diff = Self {
+            locks: (current ^ target) & Modifiers::LOCKS,
+            ..Default::default()
+        };
+
+        for (modifier, left, right, _, _) in SIDED_MODIFIERS {
+            let cur = modifier_sides(current, modifier, left | right);
+            let target = modifier_sides(target, modifier, left | right);
+
+            match (cur, target) {
+                (cur, Modifiers::NONE) => diff.extra |= cur,
+                (Modifiers::NONE, target) => diff.missing |= target,
+                // One of the sides is unknown, it can't be wrong.
+                (cur, target) if cur == modifier || target == modifier => {}
+                (cur, target) => {
+                    diff.extra |= cur - target;
+                    diff.missing |= target - cur;
+                }
+            }
+        }
+
+        let others = KEYSYM_MODIFIERS
+            .iter()
+            .fold(Modifiers::ALT_GR, |res, (modifier, _)| res | *modifier);
+        diff.extra |= (current - target) & others;
+        diff.missing |= (target - current) & others;
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.extra.is_empty() && self.missing.is_empty() && self.locks.is_empty()
+    }
+
+    /// Release the extra modifiers then press the missing ones, the locks are
+    /// left out. An extra modifier without side releases both keys.
+    ///
+    /// The keys are the ones of the evdev keymap, see `key_events_with`.
+    pub fn key_events(&self) -> Vec<KeyEvent> {
+        self.key_events_with(|_, key| key)
+    }
+
+    /// Like `key_events`, `modifier_key` gives the key actually activating a
+    /// modifier (without side) from the key of the evdev keymap.
+    pub fn key_events_with(
+        &self,
+        modifier_key: impl Fn(Modifiers, KeyCode) -> KeyCode,
+    ) -> Vec<KeyEvent> {
+        let mut key_event_vec: Vec<KeyEvent> = vec![];
+        let mut push = |modifier: Modifiers, key: KeyCode, press: bool| {
+            let key_event = KeyEvent::with_keycode(modifier_key(modifier, key), press);
+            // Both sides may be on the same key.
+            if !key_event_vec.contains(&key_event) {
+                key_event_vec.push(key_event);
+            }
+        };
+
+        for (modifier, left, right, left_phys, right_phys) in SIDED_MODIFIERS {
+            if self.extra.intersects(modifier | left) {
+                push(modifier, KeyCode::Physical(left_phys), false);
+            }
+            if self.extra.intersects(modifier | right) {
+                push(modifier, KeyCode::Physical(right_phys), false);
+            }
+        }
+        if self.extra.contains(Modifiers::ALT_GR) {
+            push(
+                Modifiers::ALT_GR,
+                KeyCode::Physical(PhysKeyCode::AltRight),
+                false,
+            );
+        }
+        for (modifier, keysym) in KEYSYM_MODIFIERS {
+            if self.extra.contains(modifier) {
+                push(modifier, KeyCode::KeySym(keysym), false);
+            }
+        }
+
+        for (modifier, left, right, left_phys, right_phys) in SIDED_MODIFIERS {
+            if self.missing.intersects(modifier | left) {
+                push(modifier, KeyCode::Physical(left_phys), true);
+            }
+            if self.missing.contains(right) {
+                push(modifier, KeyCode::Physical(right_phys), true);
+            }
+        }
+        if self.missing.contains(Modifiers::ALT_GR) {
+            push(
+                Modifiers::ALT_GR,
+                KeyCode::Physical(PhysKeyCode::AltRight),
+                true,
+            );
+        }
+        for (modifier, keysym) in KEYSYM_MODIFIERS {
+            if self.missing.contains(modifier) {
+                push(modifier, KeyCode::KeySym(keysym), true);
+            }
+        }
+
+        key_event_vec
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RawKeyEvent {
     /// The physical location of the key on an ANSI-Standard US layout
@@ -490,6 +636,32 @@ pub enum SimEvent {
     ExitThread,
     ReleaseKeys,
     Simulate(KeyEvent),
+    TypeText { text: String, options: TypeOptions },
+}
+
+/// How `type_text` types a string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypeOptions {
+    /// The pause between two chars.
+    pub key_delay: std::time::Duration,
+    /// The chars typed before waiting for the X server, then pausing for
+    /// `chunk_delay`, so the server and the applications keep up.
+    pub chunk_size: usize,
+    pub chunk_delay: std::time::Duration,
+    /// The chars missing from the keymap are looked for ahead and remapped
+    /// by batches of this size.
+    pub remap_batch: usize,
+}
+
+impl Default for TypeOptions {
+    fn default() -> Self {
+        Self {
+            key_delay: std::time::Duration::ZERO,
+            chunk_size: 256,
+            chunk_delay: std::time::Duration::from_millis(20),
+            remap_batch: 32,
+        }
+    }
 }
 
 impl TryFrom<Vec<u8>> for SimEvent {
@@ -572,6 +744,204 @@ pub enum GroupIndex {
     N4,
 }
 
+/// Rules, model, layouts, variants and options the keymap was compiled from,
+/// as stored by setxkbmap in `_XKB_RULES_NAMES`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleNames {
+    pub rules: String,
+    pub model: String,
+    pub layouts: Vec<String>,
+    pub variants: Vec<String>,
+    pub options: Vec<String>,
+}
+
+impl RuleNames {
+    /// Parse the value of `_XKB_RULES_NAMES`: five strings separated by '\0'.
+    pub fn from_property(value: &[u8]) -> Self {
+        let mut fields = value
+            .split(|&b| b == 0)
+            .map(|field| String::from_utf8_lossy(field).into_owned());
+        let mut next = || fields.next().unwrap_or_default();
+        let split = |field: String| -> Vec<String> {
+            if field.is_empty() {
+                vec![]
+            } else {
+                field.split(',').map(|ele| ele.trim().to_owned()).collect()
+            }
+        };
+
+        Self {
+            rules: next(),
+            model: next(),
+            layouts: split(next()),
+            variants: split(next()),
+            options: split(next()),
+        }
+    }
+}
+
+/// One group (layout) of the keymap.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayoutGroup {
+    pub index: u32,
+    /// Descriptive name, e.g. "French (AZERTY)".
+    pub name: String,
+    /// Layout name from the rules, e.g. "fr".
+    pub short_name: String,
+    pub variant: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayoutInfo {
+    /// None if no `_XKB_RULES_NAMES` is set on the root window.
+    pub rule_names: Option<RuleNames>,
+    pub groups: Vec<LayoutGroup>,
+    pub active_group: u32,
+    pub num_groups: u32,
+}
+
+/// The modifiers of the keyboard split by how they are active, and the
+/// effective group.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModifierState {
+    /// Held down.
+    pub base: Modifiers,
+    /// Active until the next key press.
+    pub latched: Modifiers,
+    /// Active until unlocked, e.g. CAPS.
+    pub locked: Modifiers,
+    pub group: u32,
+}
+
+impl ModifierState {
+    pub fn effective(&self) -> Modifiers {
+        self.base | self.latched | self.locked
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyboardDeviceKind {
+    Master,
+    /// Attached to a master keyboard.
+    Slave,
+    Floating,
+}
+
+/// An XInput2 keyboard device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyboardDevice {
+    pub id: u16,
+    pub name: String,
+    pub kind: KeyboardDeviceKind,
+    /// The paired master pointer of a master, the master of a slave.
+    pub attachment: u16,
+    pub enabled: bool,
+    /// The slave receiving the fake inputs of XTEST.
+    pub is_xtest: bool,
+}
+
+/// The autorepeat of the keyboard, delay and interval in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AutoRepeat {
+    pub enabled: bool,
+    /// Before the first repeat.
+    pub delay: u16,
+    /// Between two repeats.
+    pub interval: u16,
+    /// One bit per keycode.
+    pub per_key: [u8; 32],
+}
+
+impl AutoRepeat {
+    pub fn is_key_enabled(&self, keycode: u8) -> bool {
+        self.per_key[keycode as usize / 8] & (1 << (keycode % 8)) != 0
+    }
+
+    pub fn set_key_enabled(&mut self, keycode: u8, enabled: bool) {
+        let bit = 1 << (keycode % 8);
+        let byte = &mut self.per_key[keycode as usize / 8];
+        match enabled {
+            true => *byte |= bit,
+            false => *byte &= !bit,
+        }
+    }
+}
+
+/// Changes of the keyboard reported to the subscribers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyboardEvent {
+    LayoutGroupChanged {
+        old_group: u32,
+        new_group: u32,
+        name: String,
+    },
+    KeymapReloaded,
+    /// Effective modifiers, without the locks.
+    ModifiersChanged {
+        old: Modifiers,
+        new: Modifiers,
+    },
+    /// Locked CAPS, NUM and SCROLL.
+    LockStateChanged {
+        old: Modifiers,
+        new: Modifiers,
+    },
+    IndicatorChanged {
+        name: String,
+        active: bool,
+    },
+    /// The keyboard device was replaced, e.g. unplugged.
+    DeviceChanged {
+        old_device: u8,
+        new_device: u8,
+    },
+}
+
+/// A keyboard LED, e.g. "Caps Lock".
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Indicator {
+    pub index: u32,
+    pub name: String,
+    pub active: bool,
+}
+
+/// What one level of a key produces.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LevelDescription {
+    pub level: u32,
+    pub keysyms: Vec<KeySym>,
+    pub keysym_names: Vec<String>,
+    pub chars: String,
+    /// None if the level needs a modifier `Modifiers` can't express.
+    pub modifiers: Option<Modifiers>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyDescription {
+    pub phys: PhysKeyCode,
+    pub keycode: Keycode,
+    /// The levels of the key in each group.
+    pub groups: Vec<Vec<LevelDescription>>,
+}
+
+/// A keymap described per physical key, to be sent to the other side of a
+/// connection or attached to bug reports.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeymapDescription {
+    pub layout_info: LayoutInfo,
+    pub keys: Vec<KeyDescription>,
+}
+
+impl KeymapDescription {
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
 #[derive(Debug)]
 pub enum ResolvedDeadKey {
     InvalidDeadKey,
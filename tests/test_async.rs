@@ -0,0 +1,34 @@
+#![cfg(feature = "tokio")]
+
+use keyboarder::{
+    platform_impl::async_simulator::AsyncSimulator,
+    types::{KeyCode, KeyEvent, Modifiers, ServerMode},
+};
+
+#[test]
+fn test_async_simulator() {
+    std::env::set_var("DISPLAY", ":0");
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let simulator = AsyncSimulator::spawn(ServerMode::Translate).unwrap();
+        let _events = simulator.subscribe();
+
+        for chr in ['a', 'A', '1'] {
+            simulator
+                .simulate(KeyEvent {
+                    key: KeyCode::Char(chr),
+                    press: true,
+                    modifiers: Modifiers::NONE,
+                    raw_event: None,
+                })
+                .await
+                .unwrap();
+        }
+        simulator.release_keys().await.unwrap();
+
+        simulator.shutdown().await.unwrap();
+    });
+}
@@ -16,8 +16,8 @@ fn test_deadkey_ampersand() {
 
     // dead_circumflex(^) in French
     // XK_acircumflex = 0x00e2 + a
-    simulator.simulate_keysym(65106, true); // &
-    simulator.simulate_keysym(65106, false); // &
+    simulator.simulate_keysym(65106, true).unwrap(); // &
+    simulator.simulate_keysym(65106, false).unwrap(); // &
 }
 
 /// # diacritic
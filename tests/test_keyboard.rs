@@ -48,10 +48,14 @@ fn test_keyboard_when_simulate() {
     let conn = Connection::init().unwrap();
     let mut simulator = Simulator::new(&conn);
 
-    simulator.simulate_phys(PhysKeyCode::ShiftLeft, true);
-    simulator.simulate_phys(PhysKeyCode::ControlLeft, true);
-    simulator.simulate_phys(PhysKeyCode::AltLeft, true);
-    simulator.simulate_phys(PhysKeyCode::KeyQ, true);
+    simulator
+        .simulate_phys(PhysKeyCode::ShiftLeft, true)
+        .unwrap();
+    simulator
+        .simulate_phys(PhysKeyCode::ControlLeft, true)
+        .unwrap();
+    simulator.simulate_phys(PhysKeyCode::AltLeft, true).unwrap();
+    simulator.simulate_phys(PhysKeyCode::KeyQ, true).unwrap();
 
     assert_eq!(
         // keyboard.get_current_modifiers will not update when simulate
@@ -59,10 +63,16 @@ fn test_keyboard_when_simulate() {
         Modifiers::SHIFT | Modifiers::CTRL | Modifiers::ALT
     );
 
-    simulator.simulate_phys(PhysKeyCode::KeyQ, false);
-    simulator.simulate_phys(PhysKeyCode::AltLeft, false);
-    simulator.simulate_phys(PhysKeyCode::ControlLeft, false);
-    simulator.simulate_phys(PhysKeyCode::ShiftLeft, false);
+    simulator.simulate_phys(PhysKeyCode::KeyQ, false).unwrap();
+    simulator
+        .simulate_phys(PhysKeyCode::AltLeft, false)
+        .unwrap();
+    simulator
+        .simulate_phys(PhysKeyCode::ControlLeft, false)
+        .unwrap();
+    simulator
+        .simulate_phys(PhysKeyCode::ShiftLeft, false)
+        .unwrap();
 }
 
 #[test]
@@ -75,13 +85,17 @@ fn test_keyboard_altgr_when_simulate() {
     let conn = Connection::init().unwrap();
     let mut simulator = Simulator::new(&conn);
 
-    simulator.simulate_phys(PhysKeyCode::AltRight, true);
-    simulator.simulate_phys(PhysKeyCode::KeyQ, true);
+    simulator
+        .simulate_phys(PhysKeyCode::AltRight, true)
+        .unwrap();
+    simulator.simulate_phys(PhysKeyCode::KeyQ, true).unwrap();
 
     assert_eq!(simulator.get_current_modifiers(), Modifiers::ALT_GR);
 
-    simulator.simulate_phys(PhysKeyCode::KeyQ, false);
-    simulator.simulate_phys(PhysKeyCode::AltRight, false);
+    simulator.simulate_phys(PhysKeyCode::KeyQ, false).unwrap();
+    simulator
+        .simulate_phys(PhysKeyCode::AltRight, false)
+        .unwrap();
 }
 
 #[test]
@@ -168,3 +182,168 @@ fn test_keyboard_char_map() {
     dbg!(keysym);
     dbg!(chars.len());
 }
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_keyboard_plan_by_keysym() {
+    use keyboarder::keysyms::char_to_keysym;
+
+    // test it in French keyboard.
+    let _ = env_logger::try_init();
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::init().unwrap();
+
+    let kbd = conn.keyboard.borrow();
+
+    // "1" => shift + "&"
+    let plan = kbd.get_plan_by_keysym(char_to_keysym('1')).unwrap();
+    assert_eq!(plan.keycode, 10);
+    assert_eq!(plan.modifiers, Modifiers::SHIFT);
+
+    // "~" => AltGr + "é"
+    let plan = kbd.get_plan_by_keysym(char_to_keysym('~')).unwrap();
+    assert_eq!(plan.keycode, 11);
+    assert_eq!(plan.modifiers, Modifiers::ALT_GR);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_keyboard_subscribe() {
+    use keyboarder::types::KeyboardEvent;
+
+    let _ = env_logger::try_init();
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::init().unwrap();
+    let mut simulator = Simulator::new(&conn);
+    let receiver = conn.keyboard.subscribe();
+
+    simulator
+        .simulate_phys(PhysKeyCode::ShiftLeft, true)
+        .unwrap();
+    simulator
+        .simulate_phys(PhysKeyCode::ShiftLeft, false)
+        .unwrap();
+
+    while let Ok(Some(event)) = conn.poll_for_event() {
+        conn.keyboard.process_xkb_event(&conn, &event).unwrap();
+    }
+
+    assert_eq!(
+        receiver.try_recv(),
+        Ok(KeyboardEvent::ModifiersChanged {
+            old: Modifiers::NONE,
+            new: Modifiers::SHIFT
+        })
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_keyboard_modifier_state_when_simulate() {
+    let _ = env_logger::try_init();
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::init().unwrap();
+    let mut simulator = Simulator::new(&conn);
+
+    simulator
+        .simulate_phys(PhysKeyCode::ShiftLeft, true)
+        .unwrap();
+    let state = simulator.get_modifier_state();
+    assert_eq!(state.base, Modifiers::SHIFT);
+    assert!(conn
+        .keyboard
+        .get_current_modifiers()
+        .contains(Modifiers::SHIFT));
+    simulator
+        .simulate_phys(PhysKeyCode::ShiftLeft, false)
+        .unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_keyboard_set_locks() {
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::init().unwrap();
+    let keyboard = &conn.keyboard;
+    let caps = xkbcommon::xkb::LED_NAME_CAPS;
+    let locked = keyboard.get_modifier_state().locked;
+
+    keyboard.set_locks(&conn, locked | Modifiers::CAPS).unwrap();
+    while let Ok(Some(event)) = conn.poll_for_event() {
+        keyboard.process_xkb_event(&conn, &event).unwrap();
+    }
+    assert!(keyboard.is_indicator_active(caps));
+    assert!(keyboard
+        .get_indicators()
+        .iter()
+        .any(|indicator| indicator.name == caps && indicator.active));
+
+    keyboard.set_locks(&conn, locked - Modifiers::CAPS).unwrap();
+    while let Ok(Some(event)) = conn.poll_for_event() {
+        keyboard.process_xkb_event(&conn, &event).unwrap();
+    }
+    assert!(!keyboard.is_indicator_active(caps));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_keyboard_auto_repeat() {
+    std::env::set_var("DISPLAY", ":0");
+
+    // Not the thread local connection, so it is dropped at the end of the block.
+    let original = {
+        let conn = Connection::create_new().unwrap();
+        let keyboard = &conn.keyboard;
+        let original = keyboard.get_auto_repeat(&conn).unwrap();
+
+        keyboard.set_repeat_rate(&conn, 500, 50).unwrap();
+        keyboard.set_key_auto_repeat(&conn, 38, false).unwrap();
+
+        let auto_repeat = keyboard.get_auto_repeat(&conn).unwrap();
+        assert_eq!((auto_repeat.delay, auto_repeat.interval), (500, 50));
+        assert!(!auto_repeat.is_key_enabled(38));
+        original
+    };
+
+    let conn = Connection::create_new().unwrap();
+    assert_eq!(conn.keyboard.get_auto_repeat(&conn).unwrap(), original);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_keyboard_devices() {
+    use keyboarder::types::KeyboardDeviceKind;
+
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::init().unwrap();
+    let devices = conn.list_keyboard_devices().unwrap();
+    dbg!(&devices);
+
+    let core_device = conn.keyboard.get_device_id() as u16;
+    assert!(devices
+        .iter()
+        .any(|device| device.id == core_device && device.kind == KeyboardDeviceKind::Master));
+
+    let xtest = devices
+        .iter()
+        .find(|device| device.is_xtest && device.kind == KeyboardDeviceKind::Slave)
+        .unwrap();
+    assert_eq!(xtest.attachment, core_device);
+
+    let xtest_conn = Connection::create_for_device(xtest.id).unwrap();
+    assert_eq!(xtest_conn.keyboard.get_device_id() as u16, xtest.id);
+
+    // The paired master pointer is not a keyboard.
+    let core_pointer = devices
+        .iter()
+        .find(|device| device.id == core_device)
+        .unwrap()
+        .attachment;
+    assert!(!devices.iter().any(|device| device.id == core_pointer));
+    assert!(Connection::create_for_device(core_pointer).is_err());
+}
@@ -1,4 +1,4 @@
-use keyboarder::keysyms::{char_to_keysym, CHAR_KEYSYM_MAP};
+use keyboarder::keysyms::{char_to_keysym, decompose_dead_key, is_dead_keysym, CHAR_KEYSYM_MAP};
 
 #[test]
 fn test_keysyms() {
@@ -11,3 +11,24 @@ fn test_keysyms() {
     let keysym = char_to_keysym('ü');
     assert_eq!(keysym, 252);
 }
+
+#[test]
+fn test_dead_keys() {
+    assert!(is_dead_keysym(0xfe52)); // dead_circumflex
+    assert!(!is_dead_keysym(char_to_keysym('^')));
+    assert!(is_dead_keysym(0xfe93)); // dead_longsolidusoverlay
+    assert!(!is_dead_keysym(0xfe70)); // AccessX_Enable
+    assert!(is_dead_keysym(0xfe8d)); // dead_hamza
+
+    let (dead_key, base) = decompose_dead_key('â').unwrap();
+    assert_eq!((dead_key.keysym, base), (0xfe52, 'a'));
+    let (dead_key, base) = decompose_dead_key('Ü').unwrap();
+    assert_eq!((dead_key.keysym, base), (0xfe57, 'U'));
+
+    // The accent alone is the dead key then space.
+    let (dead_key, base) = decompose_dead_key('^').unwrap();
+    assert_eq!((dead_key.keysym, base), (0xfe52, ' '));
+
+    assert!(decompose_dead_key('a').is_none());
+    assert!(decompose_dead_key('€').is_none());
+}
@@ -0,0 +1,65 @@
+use keyboarder::types::RuleNames;
+
+#[test]
+fn test_rule_names_from_property() {
+    let names = RuleNames::from_property(b"evdev\0pc105\0us,ru\0,phonetic\0grp:alt_shift_toggle\0");
+
+    assert_eq!(names.rules, "evdev");
+    assert_eq!(names.model, "pc105");
+    assert_eq!(names.layouts, ["us", "ru"]);
+    assert_eq!(names.variants, ["", "phonetic"]);
+    assert_eq!(names.options, ["grp:alt_shift_toggle"]);
+}
+
+#[test]
+fn test_rule_names_without_options() {
+    let names = RuleNames::from_property(b"evdev\0pc105\0fr\0\0\0");
+
+    assert_eq!(names.layouts, ["fr"]);
+    assert!(names.variants.is_empty());
+    assert!(names.options.is_empty());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_layout_info() {
+    use keyboarder::{connection::ConnectionOps, platform_impl::Connection};
+
+    // test it in French keyboard.
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::init().unwrap();
+    let info = conn.get_layout_info().unwrap();
+
+    assert_eq!(info.num_groups as usize, info.groups.len());
+    assert_eq!(info.groups[0].short_name, "fr");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_export_keymap() {
+    use keyboarder::{
+        connection::ConnectionOps,
+        platform_impl::Connection,
+        types::{KeymapDescription, PhysKeyCode},
+    };
+
+    // test it in French keyboard.
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::init().unwrap();
+
+    let text = conn.keyboard.get_keymap_string();
+    assert!(text.starts_with("xkb_keymap {"));
+
+    let description = conn.describe_keymap().unwrap();
+    let key_q = description
+        .keys
+        .iter()
+        .find(|key| key.phys == PhysKeyCode::KeyQ)
+        .unwrap();
+    assert_eq!(key_q.groups[0][0].chars, "a");
+
+    let json = description.to_json().unwrap();
+    assert_eq!(KeymapDescription::from_json(&json).unwrap(), description);
+}
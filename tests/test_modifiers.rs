@@ -38,3 +38,64 @@ fn test_trans_mod() {
 
     assert_eq!(v, Modifiers::CTRL);
 }
+
+#[test]
+fn test_modifier_state_effective() {
+    use keyboarder::types::ModifierState;
+
+    let state = ModifierState {
+        base: Modifiers::SHIFT,
+        latched: Modifiers::NONE,
+        locked: Modifiers::CAPS,
+        group: 0,
+    };
+
+    assert_eq!(state.effective(), Modifiers::SHIFT | Modifiers::CAPS);
+}
+
+#[test]
+fn test_mod_extended_bits() {
+    let mods = Modifiers::SUPER | Modifiers::HYPER | Modifiers::LEVEL5 | Modifiers::SCROLL;
+    assert_eq!(mods.to_string(), "SUPER|HYPER|LEVEL5|SCROLL");
+    assert_eq!(Modifiers::try_from(mods.to_string()).unwrap(), mods);
+
+    let mods = Modifiers::LEFT_META | Modifiers::SHIFT;
+    assert_eq!(
+        mods.trans_positional_mods(),
+        Modifiers::META | Modifiers::SHIFT
+    );
+    assert_eq!(mods.remove_positional_mods(), Modifiers::SHIFT);
+    assert!(Modifiers::RIGHT_META.is_shortcut());
+
+    assert_eq!(
+        Modifiers::NONE.diff_modifiers(&Modifiers::LEVEL5),
+        [KeyEvent::with_keycode(
+            KeyCode::KeySym(keyboarder::keysyms::KEY_ISO_LEVEL5_SHIFT),
+            true
+        )]
+    );
+}
+
+#[test]
+fn test_modifier_diff_sides() {
+    use keyboarder::types::ModifierDiff;
+
+    let diff = ModifierDiff::new(
+        Modifiers::LEFT_SHIFT | Modifiers::CAPS,
+        Modifiers::RIGHT_SHIFT | Modifiers::CTRL,
+    );
+    assert_eq!(diff.extra, Modifiers::LEFT_SHIFT);
+    assert_eq!(diff.missing, Modifiers::RIGHT_SHIFT | Modifiers::CTRL);
+    assert_eq!(diff.locks, Modifiers::CAPS);
+    assert_eq!(
+        diff.key_events(),
+        [
+            KeyEvent::with_phys(PhysKeyCode::ShiftLeft, false),
+            KeyEvent::with_phys(PhysKeyCode::ShiftRight, true),
+            KeyEvent::with_phys(PhysKeyCode::ControlLeft, true),
+        ]
+    );
+
+    // The side of SHIFT is unknown, either key is fine.
+    assert!(ModifierDiff::new(Modifiers::SHIFT, Modifiers::RIGHT_SHIFT).is_empty());
+}
@@ -0,0 +1,286 @@
+#![cfg(target_os = "linux")]
+//! Keyboards compiled without X server, they only need xkeyboard-config.
+
+use keyboarder::{
+    keysyms::char_to_keysym,
+    platform_impl::{compose::ComposeTable, Keyboard},
+    types::{Modifiers, PhysKeyCode, RuleNames},
+};
+
+fn keyboard(layout: &str, variant: &str) -> Keyboard {
+    Keyboard::new_from_names(&RuleNames {
+        rules: "evdev".to_owned(),
+        model: "pc105".to_owned(),
+        layouts: vec![layout.to_owned()],
+        variants: vec![variant.to_owned()],
+        options: vec![],
+    })
+    .unwrap()
+}
+
+#[test]
+fn test_offline_french_plan() {
+    let kbd = keyboard("fr", "azerty");
+
+    // "1" => shift + "&"
+    let plan = kbd.get_plan_by_keysym(char_to_keysym('1')).unwrap();
+    assert_eq!(
+        kbd.get_phys_by_keycode(plan.keycode),
+        Some(PhysKeyCode::Num1)
+    );
+    assert_eq!(plan.modifiers, Modifiers::SHIFT);
+
+    let plan = kbd.get_plan_by_keysym(char_to_keysym('a')).unwrap();
+    assert_eq!(
+        kbd.get_phys_by_keycode(plan.keycode),
+        Some(PhysKeyCode::KeyQ)
+    );
+    assert_eq!(plan.modifiers, Modifiers::NONE);
+
+    // "€" => AltGr + "e"
+    let plan = kbd.get_plan_by_keysym(char_to_keysym('€')).unwrap();
+    assert_eq!(
+        kbd.get_phys_by_keycode(plan.keycode),
+        Some(PhysKeyCode::KeyE)
+    );
+    assert_eq!(plan.modifiers, Modifiers::ALT_GR);
+    // U20AC is EuroSign.
+    assert_eq!(kbd.get_plan_by_keysym(0x10020ac), Some(plan));
+}
+
+#[test]
+fn test_offline_german_nodeadkeys() {
+    let kbd = keyboard("de", "nodeadkeys");
+
+    let plan = kbd.get_plan_by_keysym(char_to_keysym('z')).unwrap();
+    assert_eq!(
+        kbd.get_phys_by_keycode(plan.keycode),
+        Some(PhysKeyCode::KeyY)
+    );
+
+    // "^" is a plain key without dead keys.
+    let plan = kbd.get_plan_by_keysym(char_to_keysym('^')).unwrap();
+    assert_eq!(
+        kbd.get_phys_by_keycode(plan.keycode),
+        Some(PhysKeyCode::BackQuote)
+    );
+    assert_eq!(plan.modifiers, Modifiers::NONE);
+}
+
+#[test]
+fn test_offline_from_string() {
+    let kbd = keyboard("us", "");
+    let kbd = Keyboard::new_from_string(&kbd.get_keymap_string()).unwrap();
+
+    let plan = kbd.get_plan_by_keysym(char_to_keysym('!')).unwrap();
+    assert_eq!(
+        kbd.get_phys_by_keycode(plan.keycode),
+        Some(PhysKeyCode::Num1)
+    );
+    assert_eq!(plan.modifiers, Modifiers::SHIFT);
+}
+
+#[test]
+fn test_offline_translate_azerty_to_qwertz() {
+    use keyboarder::{
+        platform_impl::LayoutTranslator,
+        types::{KeyCode, KeyEvent},
+    };
+
+    let translator = LayoutTranslator::new(keyboard("fr", "azerty"), keyboard("de", ""));
+    let target = translator.target();
+
+    // KeyQ is "a" in AZERTY, "a" is KeyA in QWERTZ.
+    let key_event_vec = translator
+        .translate(&KeyEvent::with_phys(PhysKeyCode::KeyQ, true))
+        .unwrap();
+    assert_eq!(
+        key_event_vec,
+        [KeyEvent::with_keycode(
+            KeyCode::RawCode(target.get_keycode_by_phys(PhysKeyCode::KeyA).unwrap()),
+            true
+        )]
+    );
+
+    // KeyW is "z" in AZERTY, "z" is KeyY in QWERTZ. Ctrl is not consumed and kept.
+    let mut key_event = KeyEvent::with_phys(PhysKeyCode::KeyW, true);
+    key_event.modifiers = Modifiers::LEFT_CTRL;
+    let key_event_vec = translator.translate(&key_event).unwrap();
+    assert_eq!(
+        key_event_vec[0].key,
+        KeyCode::RawCode(target.get_keycode_by_phys(PhysKeyCode::KeyY).unwrap())
+    );
+    assert_eq!(key_event_vec[0].modifiers, Modifiers::CTRL);
+
+    // Non-printing keys stay on the same physical key.
+    let key_event_vec = translator
+        .translate(&KeyEvent::with_phys(PhysKeyCode::F5, true))
+        .unwrap();
+    assert_eq!(
+        key_event_vec[0].key,
+        KeyCode::RawCode(target.get_keycode_by_phys(PhysKeyCode::F5).unwrap())
+    );
+
+    // The release matches the press.
+    let key_event_vec = translator
+        .translate(&KeyEvent::with_phys(PhysKeyCode::KeyQ, false))
+        .unwrap();
+    assert!(!key_event_vec[0].press);
+}
+
+#[test]
+fn test_offline_modifier_map() {
+    use keyboarder::platform_impl::modmap::ModifierMap;
+
+    let kbd = keyboard("fr", "azerty");
+    let modifier_map = kbd.get_modifier_map();
+    assert_eq!(modifier_map.to_mod_mask(Modifiers::ALT_GR), 1 << 7);
+    assert_eq!(modifier_map.to_modifiers(1 << 3), Modifiers::ALT);
+    assert_eq!(
+        modifier_map.to_mod_mask(Modifiers::SHIFT | Modifiers::META),
+        ModifierMap::default().to_mod_mask(Modifiers::SHIFT | Modifiers::META)
+    );
+
+    // AltGr moved from Mod5 to Mod3.
+    let keymap = kbd
+        .get_keymap_string()
+        .replace("modifier_map Mod5 {", "modifier_map Mod3 {");
+    let kbd = Keyboard::new_from_string(&keymap).unwrap();
+    let modifier_map = kbd.get_modifier_map();
+    assert_eq!(modifier_map.to_mod_mask(Modifiers::ALT_GR), 1 << 5);
+
+    let plan = kbd.get_plan_by_keysym(char_to_keysym('€')).unwrap();
+    assert_eq!(plan.modifiers, Modifiers::ALT_GR);
+}
+
+#[test]
+fn test_offline_plan_with_locks() {
+    let kbd = keyboard("fr", "azerty");
+
+    // CAPS locked: "a" needs shift, "A" needs nothing.
+    let plan = kbd
+        .get_plan_with_locks(char_to_keysym('a'), Modifiers::CAPS)
+        .unwrap();
+    assert_eq!(
+        kbd.get_phys_by_keycode(plan.keycode),
+        Some(PhysKeyCode::KeyQ)
+    );
+    assert_eq!(plan.modifiers, Modifiers::SHIFT);
+
+    let plan = kbd
+        .get_plan_with_locks(char_to_keysym('A'), Modifiers::CAPS)
+        .unwrap();
+    assert_eq!(plan.modifiers, Modifiers::NONE);
+
+    // The keypad digits depend on NUM, without it NUM is locked for the time
+    // of the plan.
+    let kp_1 = 0xffb1; // KP_1
+    let plan = kbd.get_plan_with_locks(kp_1, Modifiers::NUM).unwrap();
+    assert_eq!(plan.modifiers, Modifiers::NONE);
+    assert_eq!(plan.locks, None);
+    let plan = kbd.get_plan_with_locks(kp_1, Modifiers::CAPS).unwrap();
+    assert_eq!(plan.modifiers, Modifiers::NONE);
+    assert_eq!(plan.locks, Some(Modifiers::CAPS | Modifiers::NUM));
+}
+
+#[test]
+fn test_offline_dead_key_plans() {
+    let kbd = keyboard("fr", "azerty");
+
+    // No key for "â": dead_circumflex, on LeftBracket, then "a".
+    assert!(kbd
+        .get_plan_with_locks(char_to_keysym('â'), Modifiers::NONE)
+        .is_none());
+    let [dead_plan, base_plan] = kbd.get_dead_key_plans('â', Modifiers::NONE).unwrap();
+    assert_eq!(
+        kbd.get_phys_by_keycode(dead_plan.keycode),
+        Some(PhysKeyCode::LeftBracket)
+    );
+    assert_eq!(
+        kbd.get_phys_by_keycode(base_plan.keycode),
+        Some(PhysKeyCode::KeyQ)
+    );
+    assert_eq!(base_plan.modifiers, Modifiers::NONE);
+}
+
+#[test]
+fn test_offline_compose_plans() {
+    let kbd = Keyboard::new_from_names(&RuleNames {
+        rules: "evdev".to_owned(),
+        model: "pc105".to_owned(),
+        layouts: vec!["us".to_owned()],
+        variants: vec!["".to_owned()],
+        options: vec!["compose:ralt".to_owned()],
+    })
+    .unwrap();
+    assert!(kbd.get_compose_plans('≠', Modifiers::NONE).is_none());
+
+    let table = ComposeTable::new_from_string(
+        kbd.get_context(),
+        r#"
+<Multi_key> <equal> <slash> : "≠" notequal
+<Multi_key> <slash> <slash> <equal> : "≠" notequal
+<Multi_key> <macron> <udiaeresis> : "ǖ" U01D6
+<Multi_key> <underscore> <quotedbl> <u> : "ǖ" U01D6
+"#,
+        "C",
+    )
+    .unwrap();
+    kbd.set_compose_table(Some(table.clone()));
+
+    // The shortest sequence.
+    let plans = kbd.get_compose_plans('≠', Modifiers::NONE).unwrap();
+    let physs: Vec<_> = plans
+        .iter()
+        .map(|plan| kbd.get_phys_by_keycode(plan.keycode))
+        .collect();
+    assert_eq!(
+        physs,
+        vec![
+            Some(PhysKeyCode::AltRight),
+            Some(PhysKeyCode::Equal),
+            Some(PhysKeyCode::Slash)
+        ]
+    );
+
+    // No macron nor udiaeresis on the layout.
+    let plans = kbd.get_compose_plans('ǖ', Modifiers::NONE).unwrap();
+    assert_eq!(plans.len(), 4);
+    assert_eq!(plans[1].modifiers, Modifiers::SHIFT);
+
+    assert!(kbd.get_compose_plans('€', Modifiers::NONE).is_none());
+    // The sequences stay valid while the keysyms are still there.
+    let (multi_key, equal, slash) = (0xff20, 0x3d, 0x2f);
+    let sequences = table.shortest_sequences(vec![slash, multi_key, equal]);
+    assert_eq!(
+        sequences.get('≠'),
+        Some([multi_key, equal, slash].as_slice())
+    );
+    assert!(sequences.is_valid_for(&[slash, equal, 0x5f, multi_key]));
+    assert!(!sequences.is_valid_for(&[slash, multi_key]));
+}
+
+#[test]
+fn test_offline_modifier_keys() {
+    use keyboarder::types::{KeyCode, KeyEvent, ModifierDiff};
+
+    // AltGr on Menu, AltRight is Alt_R.
+    let kbd = Keyboard::new_from_names(&RuleNames {
+        rules: "evdev".to_owned(),
+        model: "pc105".to_owned(),
+        layouts: vec!["us".to_owned()],
+        variants: vec!["".to_owned()],
+        options: vec!["lv3:menu_switch".to_owned()],
+    })
+    .unwrap();
+    let menu = kbd.get_keycode_by_phys(PhysKeyCode::Menu).unwrap();
+
+    let diff = ModifierDiff::new(Modifiers::NONE, Modifiers::ALT_GR | Modifiers::SHIFT);
+    assert_eq!(
+        diff.key_events_with(|modifier, key| kbd.get_modifier_key(modifier, key)),
+        [
+            KeyEvent::with_phys(PhysKeyCode::ShiftLeft, true),
+            KeyEvent::with_keycode(KeyCode::RawCode(menu), true),
+        ]
+    );
+}
@@ -16,17 +16,21 @@ fn test_shortcut() {
     let conn = Connection::init().unwrap();
     let mut simulator = Simulator::new(&conn);
     // shift + delete = 1 in French
-    simulator.simulate_key_event(&KeyEvent {
-        key: KeyCode::KeySym(49),
-        press: true,
-        modifiers: Modifiers::SHIFT,
-        raw_event: None,
-    });
+    simulator
+        .simulate_key_event(&KeyEvent {
+            key: KeyCode::KeySym(49),
+            press: true,
+            modifiers: Modifiers::SHIFT,
+            raw_event: None,
+        })
+        .unwrap();
 
-    simulator.simulate_key_event(&KeyEvent {
-        key: KeyCode::KeySym(49),
-        press: false,
-        modifiers: Modifiers::SHIFT,
-        raw_event: None,
-    });
+    simulator
+        .simulate_key_event(&KeyEvent {
+            key: KeyCode::KeySym(49),
+            press: false,
+            modifiers: Modifiers::SHIFT,
+            raw_event: None,
+        })
+        .unwrap();
 }
@@ -1,8 +1,12 @@
+use std::os::unix::io::AsRawFd;
+
 use keyboarder::{
     connection::ConnectionOps,
-    platform_impl::{Connection, Simulator},
-    simulate::Simulate,
-    types::{KeyCode, KeyEvent, Modifiers, PhysKeyCode},
+    error::KeyboarderError,
+    keysyms::char_to_keysym,
+    platform_impl::{remap::RemapPool, Connection, Simulator},
+    simulate::{encode_frame, FrameDecoder, Simulate},
+    types::{KeyCode, KeyEvent, Modifiers, PhysKeyCode, ServerMode, SimEvent, TypeOptions},
 };
 /// 1
 /// a
@@ -16,22 +20,22 @@ fn test_char_keycode() {
     let mut simulator = Simulator::new(&conn);
 
     // & in French, 1 in US
-    simulator.simulate_keycode(10, true);
-    simulator.simulate_keycode(10, false);
+    simulator.simulate_keycode(10, true).unwrap();
+    simulator.simulate_keycode(10, false).unwrap();
 
     // & in French
-    simulator.simulate_keycode(10, true);
-    simulator.simulate_keycode(10, false);
-
-    simulator.simulate_char_without_modifiers('1');
-    simulator.simulate_char_without_modifiers('1');
-    simulator.simulate_char_without_modifiers('!');
-    simulator.simulate_char_without_modifiers('!');
-
-    simulator.simulate_char_without_modifiers('¹');
-    simulator.simulate_char_without_modifiers('¹');
-    simulator.simulate_char_without_modifiers('¡');
-    simulator.simulate_char_without_modifiers('¡');
+    simulator.simulate_keycode(10, true).unwrap();
+    simulator.simulate_keycode(10, false).unwrap();
+
+    simulator.simulate_char_without_modifiers('1').unwrap();
+    simulator.simulate_char_without_modifiers('1').unwrap();
+    simulator.simulate_char_without_modifiers('!').unwrap();
+    simulator.simulate_char_without_modifiers('!').unwrap();
+
+    simulator.simulate_char_without_modifiers('¹').unwrap();
+    simulator.simulate_char_without_modifiers('¹').unwrap();
+    simulator.simulate_char_without_modifiers('¡').unwrap();
+    simulator.simulate_char_without_modifiers('¡').unwrap();
 }
 
 /// # char + AltGr/Shift
@@ -45,18 +49,22 @@ fn test_char_keycode_with_modifier() {
     let mut simulator = Simulator::new(&conn);
 
     // shift + & = 1 in French
-    simulator.simulate_key_event(&KeyEvent {
-        key: KeyCode::RawCode(10),
-        press: true,
-        modifiers: Modifiers::SHIFT,
-        raw_event: None,
-    });
-    simulator.simulate_key_event(&KeyEvent {
-        key: KeyCode::RawCode(10),
-        press: false,
-        modifiers: Modifiers::SHIFT,
-        raw_event: None,
-    });
+    simulator
+        .simulate_key_event(&KeyEvent {
+            key: KeyCode::RawCode(10),
+            press: true,
+            modifiers: Modifiers::SHIFT,
+            raw_event: None,
+        })
+        .unwrap();
+    simulator
+        .simulate_key_event(&KeyEvent {
+            key: KeyCode::RawCode(10),
+            press: false,
+            modifiers: Modifiers::SHIFT,
+            raw_event: None,
+        })
+        .unwrap();
 }
 
 #[test]
@@ -68,18 +76,22 @@ fn test_char_by_phys() {
 
     // shift + KeyQ = "Q" in French
     // shift + KeyA = "A" in US
-    simulator.simulate_key_event(&KeyEvent {
-        key: KeyCode::Physical(PhysKeyCode::KeyQ),
-        press: true,
-        modifiers: Modifiers::SHIFT,
-        raw_event: None,
-    });
-    simulator.simulate_key_event(&KeyEvent {
-        key: KeyCode::Physical(PhysKeyCode::KeyQ),
-        press: false,
-        modifiers: Modifiers::SHIFT,
-        raw_event: None,
-    });
+    simulator
+        .simulate_key_event(&KeyEvent {
+            key: KeyCode::Physical(PhysKeyCode::KeyQ),
+            press: true,
+            modifiers: Modifiers::SHIFT,
+            raw_event: None,
+        })
+        .unwrap();
+    simulator
+        .simulate_key_event(&KeyEvent {
+            key: KeyCode::Physical(PhysKeyCode::KeyQ),
+            press: false,
+            modifiers: Modifiers::SHIFT,
+            raw_event: None,
+        })
+        .unwrap();
 }
 
 #[test]
@@ -91,10 +103,14 @@ fn test_keysym() {
     let mut simulator = Simulator::new(&conn);
 
     // & in French, 1 in US
-    simulator.simulate_phys(PhysKeyCode::ControlLeft, true);
-    simulator.simulate_keysym(97, true);
-    simulator.simulate_keysym(97, false);
-    simulator.simulate_phys(PhysKeyCode::ControlLeft, false);
+    simulator
+        .simulate_phys(PhysKeyCode::ControlLeft, true)
+        .unwrap();
+    simulator.simulate_keysym(97, true).unwrap();
+    simulator.simulate_keysym(97, false).unwrap();
+    simulator
+        .simulate_phys(PhysKeyCode::ControlLeft, false)
+        .unwrap();
 }
 
 #[test]
@@ -103,10 +119,293 @@ fn test_simulate_get_modifier() {
 
     let conn = Connection::init().unwrap();
     let simulator = Simulator::new(&conn);
-    // simulator.simulate_phys(PhysKeyCode::ControlLeft, true);
+    // simulator.simulate_phys(PhysKeyCode::ControlLeft, true).unwrap();
     //assert_eq!(Modifiers::CTRL, simulator.get_current_modifiers());
-    //simulator.simulate_phys(PhysKeyCode::ControlLeft, false);
+    //simulator.simulate_phys(PhysKeyCode::ControlLeft, false).unwrap();
 
-    //simulator.simulate_phys(PhysKeyCode::ControlLeft, true);
+    //simulator.simulate_phys(PhysKeyCode::ControlLeft, true).unwrap();
     // dbg!(simulator.get_current_modifiers());
 }
+
+/// # chars in another group
+/// test it with `setxkbmap us,ru`
+#[test]
+fn test_char_in_inactive_group() {
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::init().unwrap();
+    let mut simulator = Simulator::new(&conn);
+
+    simulator.simulate_char_without_modifiers('ж').unwrap();
+    simulator.simulate_char_without_modifiers('Ж').unwrap();
+    simulator.simulate_char_without_modifiers('a').unwrap();
+}
+
+/// Shift held by another simulator stands for the physical keyboard.
+#[test]
+fn test_isolate_physical_mods() {
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::init().unwrap();
+    let mut physical = Simulator::new(&conn);
+    let mut simulator = Simulator::new(&conn);
+    simulator.isolate_physical_mods = true;
+
+    physical
+        .simulate_phys(PhysKeyCode::ShiftLeft, true)
+        .unwrap();
+    assert!(simulator
+        .get_physical_mods()
+        .unwrap()
+        .contains(Modifiers::SHIFT));
+    // Still held physically while the simulator holds the other Shift.
+    simulator
+        .simulate_phys(PhysKeyCode::ShiftRight, true)
+        .unwrap();
+    assert!(simulator
+        .get_physical_mods()
+        .unwrap()
+        .contains(Modifiers::SHIFT));
+    simulator
+        .simulate_phys(PhysKeyCode::ShiftRight, false)
+        .unwrap();
+
+    let internal_mods = conn.keyboard.get_internal_mods(&conn).unwrap();
+    simulator
+        .isolated(|simulator| {
+            simulator.simulate_char_without_modifiers('a')?;
+            simulator.simulate_char_without_modifiers('b')
+        })
+        .unwrap();
+    assert_eq!(
+        conn.keyboard.get_internal_mods(&conn).unwrap(),
+        internal_mods
+    );
+
+    // Nothing is hidden outside `isolated`.
+    simulator.release_modifiers().unwrap();
+    assert_eq!(
+        conn.keyboard.get_internal_mods(&conn).unwrap(),
+        internal_mods
+    );
+
+    physical
+        .simulate_phys(PhysKeyCode::ShiftLeft, false)
+        .unwrap();
+}
+
+#[test]
+fn test_simulate_errors() {
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::init().unwrap();
+    let mut simulator = Simulator::new(&conn);
+
+    assert!(matches!(
+        simulator.simulate_keycode(7, true),
+        Err(KeyboarderError::InvalidKeycode(7))
+    ));
+    assert!(matches!(
+        simulator.simulate_keysym(0x10fffff, true),
+        Err(KeyboarderError::NoKeycodeForKeysym { .. })
+    ));
+    assert!(matches!(
+        simulator.simulate_server(&KeyEvent {
+            key: KeyCode::Char('a'),
+            press: true,
+            modifiers: Modifiers::NONE,
+            raw_event: None,
+        }),
+        Err(KeyboarderError::ModeNotSet)
+    ));
+
+    simulator.mode = Some(ServerMode::Translate);
+    assert!(matches!(
+        simulator.simulate_server(&KeyEvent::with_keycode(KeyCode::RawCode(38), true)),
+        Err(KeyboarderError::UnexpectedKeyEvent(_))
+    ));
+}
+
+#[test]
+fn test_frame_decoder() {
+    let events = vec![
+        SimEvent::Simulate(KeyEvent {
+            key: KeyCode::Composed("a composed text longer than the old 64 bytes buffer".repeat(4)),
+            press: true,
+            modifiers: Modifiers::SHIFT,
+            raw_event: None,
+        }),
+        SimEvent::ReleaseKeys,
+        SimEvent::ExitThread,
+    ];
+    let bytes: Vec<u8> = events
+        .iter()
+        .flat_map(|event| encode_frame(event).unwrap())
+        .collect();
+
+    // Merged and split reads.
+    let mut decoder = FrameDecoder::default();
+    let mut decoded = vec![];
+    for chunk in bytes.chunks(7) {
+        decoder.push(chunk);
+        while let Some(event) = decoder.next_event().unwrap() {
+            decoded.push(event);
+        }
+    }
+    assert_eq!(decoded, events);
+}
+
+#[test]
+fn test_simulator_handles() {
+    std::env::set_var("DISPLAY", ":0");
+
+    let first = Simulator::spawn_server(ServerMode::Translate).unwrap();
+    let second = Simulator::spawn_server(ServerMode::Translate).unwrap();
+
+    for chr in ['a', 'b'] {
+        let key_event = KeyEvent {
+            key: KeyCode::Char(chr),
+            press: true,
+            modifiers: Modifiers::NONE,
+            raw_event: None,
+        };
+        first.simulate(key_event.clone()).unwrap();
+        second.simulate(key_event).unwrap();
+    }
+
+    first.shutdown().unwrap();
+    assert!(!first.is_running());
+    assert!(second.is_running());
+    // The second one is stopped by drop.
+}
+
+/// Without simulator thread, like in an external event loop.
+#[test]
+fn test_submit_and_dispatch() {
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::with_simulator(ServerMode::Translate).unwrap();
+    assert!(conn.as_raw_fd() >= 0);
+
+    for chr in ['a', 'b'] {
+        conn.submit(SimEvent::Simulate(KeyEvent {
+            key: KeyCode::Char(chr),
+            press: true,
+            modifiers: Modifiers::NONE,
+            raw_event: None,
+        }));
+    }
+    conn.submit(SimEvent::ReleaseKeys);
+    assert!(conn.has_pending_events());
+
+    assert!(!conn.dispatch().unwrap());
+    assert!(!conn.has_pending_events());
+}
+
+#[test]
+fn test_type_text() {
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::init().unwrap();
+    let mut simulator = Simulator::new(&conn);
+
+    let options = TypeOptions {
+        key_delay: std::time::Duration::from_millis(1),
+        chunk_size: 4,
+        ..Default::default()
+    };
+    simulator
+        .type_text("Hello World!\r\n\tAZERTY 123\n", &options)
+        .unwrap();
+    assert_eq!(
+        simulator.get_current_modifiers() - Modifiers::LOCKS,
+        Modifiers::NONE
+    );
+
+    let handle = Simulator::spawn_server(ServerMode::Translate).unwrap();
+    handle
+        .type_text(&"abc ".repeat(2500), TypeOptions::default())
+        .unwrap();
+    handle.shutdown().unwrap();
+}
+
+/// More exotic chars than reserved keycodes, the mapping comes back on drop.
+#[test]
+fn test_remap_pool() {
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::init().unwrap();
+    let get_mapping = || {
+        let cookie = conn.conn().send_request(&xcb::x::GetKeyboardMapping {
+            first_keycode: 250,
+            count: 3,
+        });
+        let reply = conn.conn().wait_for_reply(cookie).unwrap();
+        reply.keysyms().to_vec()
+    };
+    let original = get_mapping();
+
+    let mut simulator = Simulator::new(&conn);
+    simulator.remap_pool = RemapPool::with_reserved(250..=252);
+    for chr in ['ж', 'Ж', 'ф', 'Ф', 'ж', 'ש'] {
+        simulator.simulate_char_without_modifiers(chr).unwrap();
+    }
+    assert_eq!(simulator.remap_pool.len(), 3);
+
+    drop(simulator);
+    assert_eq!(get_mapping(), original);
+}
+
+/// The keycodes between the lent ones keep their mapping.
+#[test]
+fn test_remap_batch_runs() {
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::init().unwrap();
+    let get_mapping = || {
+        let cookie = conn.conn().send_request(&xcb::x::GetKeyboardMapping {
+            first_keycode: 250,
+            count: 3,
+        });
+        let reply = conn.conn().wait_for_reply(cookie).unwrap();
+        let per = reply.keysyms_per_keycode() as usize;
+        reply
+            .keysyms()
+            .chunks(per)
+            .map(|keysyms| keysyms.to_vec())
+            .collect::<Vec<_>>()
+    };
+    let original = get_mapping();
+
+    let mut pool = RemapPool::default();
+    let keysyms = [char_to_keysym('ж'), char_to_keysym('ф')];
+    let num = pool
+        .remap_batch(conn.conn(), &keysyms, &[250, 252])
+        .unwrap();
+    assert_eq!(num, 2);
+    let mapping = get_mapping();
+    assert_eq!(mapping[0][0], keysyms[0]);
+    assert_eq!(mapping[1], original[1]);
+    assert_eq!(mapping[2][0], keysyms[1]);
+
+    pool.restore(conn.conn()).unwrap();
+    assert_eq!(get_mapping(), original);
+}
+
+#[test]
+fn test_type_text_remap_batch() {
+    std::env::set_var("DISPLAY", ":0");
+
+    let conn = Connection::init().unwrap();
+    let mut simulator = Simulator::new(&conn);
+    simulator.remap_pool = RemapPool::with_reserved(245..=252);
+
+    let options = TypeOptions {
+        remap_batch: 4,
+        ..Default::default()
+    };
+    simulator
+        .type_text("你好，世界！这是一个测试。😀😁", &options)
+        .unwrap();
+    assert_eq!(simulator.remap_pool.len(), 8);
+}